@@ -0,0 +1,68 @@
+use std::{env, fs, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use email::mime::{
+    mime_encode, Alternative, ContentTransferEncoding, ContentType, MimeEncoder,
+};
+use tokio::{io, runtime::Runtime};
+
+const ATTACHMENT_SIZE: usize = 32 << 20;
+
+fn attachment() -> PathBuf {
+    let path = env::temp_dir().join("email-bench-attachment.bin");
+    let data: Vec<u8> = (0..ATTACHMENT_SIZE).map(|i| (i * 31) as u8).collect();
+    fs::write(&path, data).unwrap();
+    path
+}
+
+fn encode(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let path = attachment();
+    let attach = [Alternative::from_path(&path)];
+
+    let mut group = c.benchmark_group("encode 32MiB attachment");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(ATTACHMENT_SIZE as u64));
+
+    // Holds the raw file and the whole encoded message in memory.
+    group.bench_function("mime_encode", |b| {
+        b.to_async(&rt).iter(|| async {
+            let encoded = mime_encode(
+                "a@example.com",
+                "b@example.com",
+                "bench",
+                ContentTransferEncoding::Base64,
+                ContentType::MultipartMixed,
+                "",
+                Some(&attach),
+                None,
+            )
+            .await
+            .unwrap();
+            io::copy(&mut &encoded[..], &mut io::sink()).await.unwrap();
+        })
+    });
+
+    // Holds one chunk at a time.
+    group.bench_function("MimeEncoder", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut encoder = MimeEncoder::new(
+                "a@example.com",
+                "b@example.com",
+                "bench",
+                ContentTransferEncoding::Base64,
+                ContentType::MultipartMixed,
+                "",
+                Some(&attach),
+                None,
+            );
+            io::copy(&mut encoder, &mut io::sink()).await.unwrap();
+        })
+    });
+
+    group.finish();
+    fs::remove_file(path).ok();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,519 @@
+use std::{fmt, str::FromStr};
+
+use crate::mime::encode_words;
+
+/// Longest local part and domain allowed by RFC 5321.
+const MAX_LOCAL_LENGTH: usize = 64;
+const MAX_DOMAIN_LENGTH: usize = 255;
+
+/// `specials` of RFC 5322; everything else printable is `atext`.
+const SPECIALS: &[u8] = b"()<>[]:;@\\,.\"";
+
+/// A single mailbox, `Display Name <local@domain>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mailbox {
+    pub name: Option<String>,
+    /// Local part without quoting.
+    pub local: String,
+    /// ASCII domain, internationalized labels already in punycode.
+    pub domain: String,
+}
+
+/// A mailbox or a named group of them, as found in `To` and `Cc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Mailbox(Mailbox),
+    Group(String, Vec<Mailbox>),
+}
+
+impl Mailbox {
+    /// Builds a mailbox from a display name and a bare `local@domain`.
+    pub fn new(name: Option<&str>, addr: &str) -> Result<Self, &'static str> {
+        check_controls(addr)?;
+        let mut p = Parser::new(addr);
+        let (local, domain) = p.addr_spec()?;
+        p.end()?;
+        if let Some(name) = name {
+            check_controls(name)?;
+        }
+        Ok(Self {
+            name: name.filter(|n| !n.is_empty()).map(String::from),
+            local,
+            domain,
+        })
+    }
+
+    /// `local@domain` as used in `MAIL FROM` and `RCPT TO`.
+    pub fn addr_spec(&self) -> String {
+        if is_dot_atom(&self.local) {
+            format!("{}@{}", self.local, self.domain)
+        } else {
+            format!("{}@{}", quote(&self.local), self.domain)
+        }
+    }
+
+    /// The domain with punycode labels decoded, for display.
+    pub fn domain_unicode(&self) -> String {
+        idna::domain_to_unicode(&self.domain).0
+    }
+}
+
+impl Address {
+    /// The mailbox itself, or every member of a group.
+    pub fn mailboxes(&self) -> &[Mailbox] {
+        match self {
+            Address::Mailbox(m) => std::slice::from_ref(m),
+            Address::Group(_, members) => members,
+        }
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} <{}>", phrase(name), self.addr_spec()),
+            None => f.write_str(&self.addr_spec()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Mailbox(m) => m.fmt(f),
+            Address::Group(name, members) => {
+                write!(f, "{}:", phrase(name))?;
+                for (i, m) in members.iter().enumerate() {
+                    f.write_str(if i == 0 { " " } else { ", " })?;
+                    m.fmt(f)?;
+                }
+                f.write_str(";")
+            }
+        }
+    }
+}
+
+impl FromStr for Mailbox {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_controls(s)?;
+        let mut p = Parser::new(s);
+        match p.address()? {
+            Address::Mailbox(m) => {
+                p.end()?;
+                Ok(m)
+            }
+            Address::Group(..) => Err("group where a mailbox is expected"),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_controls(s)?;
+        let mut p = Parser::new(s);
+        let address = p.address()?;
+        p.end()?;
+        Ok(address)
+    }
+}
+
+/// Parses a comma separated address list such as a `To` header value.
+pub fn parse_address_list(s: &str) -> Result<Vec<Address>, &'static str> {
+    check_controls(s)?;
+    let mut p = Parser::new(s);
+    let mut list = Vec::new();
+    loop {
+        list.push(p.address()?);
+        p.skip_cfws()?;
+        match p.peek() {
+            None => return Ok(list),
+            Some(b',') => p.pos += 1,
+            Some(_) => return Err("unexpected character in address"),
+        }
+    }
+}
+
+/// Formats an address list for a header, separated by commas.
+pub fn format_address_list(list: &[Address]) -> String {
+    list.iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Header values are one line, so CR, LF and other controls never belong in
+/// an address and would let it inject headers or SMTP commands.
+fn check_controls(s: &str) -> Result<(), &'static str> {
+    if s.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err("control character in address");
+    }
+    Ok(())
+}
+
+fn is_atext(b: u8) -> bool {
+    b >= 0x80 || (b.is_ascii_graphic() && !SPECIALS.contains(&b))
+}
+
+fn is_dot_atom(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.').all(|a| {
+            !a.is_empty() && a.bytes().all(|b| is_atext(b) && b < 0x80)
+        })
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A display name as atoms where possible, quoted if it has specials and
+/// as encoded words if it is not ASCII.
+fn phrase(name: &str) -> String {
+    if !name.is_ascii() {
+        encode_words(name)
+    } else if name
+        .split(' ')
+        .all(|w| !w.is_empty() && w.bytes().all(is_atext))
+    {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { s, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn end(&mut self) -> Result<(), &'static str> {
+        self.skip_cfws()?;
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err("unexpected character in address"),
+        }
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_cfws(&mut self) -> Result<(), &'static str> {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t') => self.pos += 1,
+                Some(b'(') => self.comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn comment(&mut self) -> Result<(), &'static str> {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None => return Err("unterminated comment in address"),
+                Some(b'(') => depth += 1,
+                Some(b')') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return Ok(());
+                    }
+                }
+                Some(b'\\') => self.pos += 1,
+                Some(_) => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// A run of atext, with dots if `dots` is set.
+    fn atom(&mut self, dots: bool) -> &'a str {
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            if !(is_atext(b) || (dots && b == b'.')) {
+                break;
+            }
+            self.pos += 1;
+        }
+        &self.s[start..self.pos]
+    }
+
+    fn quoted(&mut self) -> Result<String, &'static str> {
+        self.pos += 1;
+        let mut text = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err("unterminated quoted string in address"),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b) => text.push(b),
+                        None => {
+                            return Err("unterminated quoted string in address")
+                        }
+                    }
+                }
+                Some(b) => text.push(b),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8(text).map_err(|_| "invalid quoted string in address")
+    }
+
+    /// Words of a display name. Dots are accepted as in `John Q. Public`.
+    fn phrase(&mut self) -> Result<Vec<String>, &'static str> {
+        let mut words = Vec::new();
+        loop {
+            self.skip_cfws()?;
+            match self.peek() {
+                Some(b'"') => words.push(self.quoted()?),
+                Some(b) if is_atext(b) || b == b'.' => {
+                    words.push(self.atom(true).to_string())
+                }
+                _ => return Ok(words),
+            }
+        }
+    }
+
+    fn address(&mut self) -> Result<Address, &'static str> {
+        self.skip_cfws()?;
+        let start = self.pos;
+        let words = self.phrase()?;
+        let name = words.join(" ");
+        match self.peek() {
+            Some(b':') if !words.is_empty() => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_cfws()?;
+                if self.peek() != Some(b';') {
+                    loop {
+                        members.push(self.mailbox()?);
+                        self.skip_cfws()?;
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            Some(b';') => break,
+                            _ => return Err("unterminated group in address"),
+                        }
+                    }
+                }
+                self.pos += 1;
+                Ok(Address::Group(name, members))
+            }
+            Some(b'<') => {
+                let (local, domain) = self.angle_addr()?;
+                Ok(Address::Mailbox(Mailbox {
+                    name: Some(name).filter(|n| !n.is_empty()),
+                    local,
+                    domain,
+                }))
+            }
+            _ => {
+                self.pos = start;
+                let (local, domain) = self.addr_spec()?;
+                Ok(Address::Mailbox(Mailbox {
+                    name: None,
+                    local,
+                    domain,
+                }))
+            }
+        }
+    }
+
+    fn mailbox(&mut self) -> Result<Mailbox, &'static str> {
+        match self.address()? {
+            Address::Mailbox(m) => Ok(m),
+            Address::Group(..) => Err("nested group in address"),
+        }
+    }
+
+    fn angle_addr(&mut self) -> Result<(String, String), &'static str> {
+        self.pos += 1;
+        let addr = self.addr_spec()?;
+        if self.peek() != Some(b'>') {
+            return Err("missing > in address");
+        }
+        self.pos += 1;
+        Ok(addr)
+    }
+
+    fn addr_spec(&mut self) -> Result<(String, String), &'static str> {
+        self.skip_cfws()?;
+        let local = match self.peek() {
+            Some(b'"') => self.quoted()?,
+            _ => {
+                let local = self.atom(true);
+                if !is_dot_atom(local) {
+                    return Err("invalid local part in address");
+                }
+                local.to_string()
+            }
+        };
+        if !local.is_ascii() {
+            return Err("non-ASCII local part in address");
+        }
+        if local.is_empty() || local.len() > MAX_LOCAL_LENGTH {
+            return Err("invalid local part length in address");
+        }
+        self.skip_cfws()?;
+        if self.peek() != Some(b'@') {
+            return Err("missing @ in address");
+        }
+        self.pos += 1;
+        self.skip_cfws()?;
+        let domain = match self.peek() {
+            Some(b'[') => self.domain_literal()?,
+            _ => to_ascii_domain(self.atom(true))?,
+        };
+        self.skip_cfws()?;
+        Ok((local, domain))
+    }
+
+    fn domain_literal(&mut self) -> Result<String, &'static str> {
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                Some(b']') => break,
+                Some(b) if b.is_ascii_graphic() && b != b'[' && b != b'\\' => {
+                    self.pos += 1
+                }
+                _ => return Err("invalid domain literal in address"),
+            }
+        }
+        self.pos += 1;
+        Ok(self.s[start..self.pos].to_string())
+    }
+}
+
+/// Converts internationalized labels to punycode and checks the result is
+/// a hostname made of letters, digits and hyphens.
+fn to_ascii_domain(domain: &str) -> Result<String, &'static str> {
+    let ascii = idna::domain_to_ascii(domain)
+        .map_err(|_| "invalid domain in address")?;
+    let valid = !ascii.is_empty()
+        && ascii.len() <= MAX_DOMAIN_LENGTH
+        && ascii.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    if !valid {
+        return Err("invalid domain in address");
+    }
+    Ok(ascii)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_mailboxes() {
+        let m: Mailbox = "JCKeep <root@jckeep.top>".parse().unwrap();
+        assert_eq!(m.name.as_deref(), Some("JCKeep"));
+        assert_eq!(m.addr_spec(), "root@jckeep.top");
+
+        let m: Mailbox = "\"Keep, J. C.\" <root@jckeep.top>".parse().unwrap();
+        assert_eq!(m.name.as_deref(), Some("Keep, J. C."));
+        assert_eq!(m.to_string(), "\"Keep, J. C.\" <root@jckeep.top>");
+
+        let m: Mailbox =
+            "John Q. Public <jqp@example.com> (work)".parse().unwrap();
+        assert_eq!(m.name.as_deref(), Some("John Q. Public"));
+
+        let m: Mailbox = "\"john \\\"jd\\\" doe\"@example.com".parse().unwrap();
+        assert_eq!(m.local, "john \"jd\" doe");
+        assert_eq!(m.addr_spec(), "\"john \\\"jd\\\" doe\"@example.com");
+
+        let m: Mailbox = "user@[192.0.2.1]".parse().unwrap();
+        assert_eq!(m.domain, "[192.0.2.1]");
+    }
+
+    #[test]
+    fn internationalized() {
+        let m: Mailbox = "张三 <zhang@例子.测试>".parse().unwrap();
+        assert_eq!(m.domain, "xn--fsqu00a.xn--0zwm56d");
+        assert_eq!(m.domain_unicode(), "例子.测试");
+        assert_eq!(
+            m.to_string(),
+            format!("{} <zhang@xn--fsqu00a.xn--0zwm56d>", encode_words("张三"))
+        );
+        assert!("张三@example.com".parse::<Mailbox>().is_err());
+    }
+
+    #[test]
+    fn parse_lists_and_groups() {
+        let list = parse_address_list(
+            "a@example.com, Team: b@example.com, C <c@example.com>;, \
+             Undisclosed recipients:;",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[1].mailboxes().len(), 2);
+        assert_eq!(list[2].mailboxes().len(), 0);
+        assert_eq!(
+            format_address_list(&list),
+            "a@example.com, Team: b@example.com, C <c@example.com>;, \
+             Undisclosed recipients:;"
+        );
+    }
+
+    #[test]
+    fn reject_malformed() {
+        for s in [
+            "",
+            "plain",
+            "a@",
+            "@example.com",
+            "a..b@example.com",
+            "a@-example.com",
+            "a@exa_mple.com",
+            "<a@example.com",
+            "\"open@example.com",
+            "a@example.com>",
+            "a@example.com, ",
+            "G: a@example.com",
+        ] {
+            assert!(parse_address_list(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn reject_injection() {
+        for s in [
+            "a@example.com\r\nRCPT TO:<victim@example.com>",
+            "a@example.com>\r\nDATA",
+            "Eve\n <a@example.com>",
+            "a@example.com\0",
+            "\"a\r\nb\"@example.com",
+        ] {
+            assert!(parse_address_list(s).is_err(), "{:?}", s);
+        }
+        assert!(Mailbox::new(Some("Eve\r\nBcc: x@y"), "a@example.com").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use subtle::ConstantTimeEq;
+use tokio::fs;
+
+/// A stored password.
+#[derive(Clone, PartialEq, Eq)]
+enum Secret {
+    /// `{PLAIN}` followed by the password, which CRAM-MD5 needs.
+    Plain(String),
+    /// A bcrypt hash such as `$2b$12$...`.
+    Bcrypt(String),
+}
+
+impl Secret {
+    fn parse(secret: &str) -> Option<Self> {
+        if let Some(password) = secret.strip_prefix("{PLAIN}") {
+            Some(Secret::Plain(password.to_string()))
+        } else if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|p| secret.starts_with(p))
+        {
+            Some(Secret::Bcrypt(secret.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Users allowed to log in, read from a file of `user:secret` lines. A
+/// secret is `{PLAIN}` followed by the password, or a bcrypt hash. Blank
+/// lines and lines starting with `#` are skipped.
+#[derive(Default)]
+pub struct Credentials {
+    users: HashMap<String, Secret>,
+}
+
+/// Leaves out the secrets.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut users: Vec<&str> =
+            self.users.keys().map(String::as_str).collect();
+        users.sort_unstable();
+        f.debug_struct("Credentials")
+            .field("users", &users)
+            .finish()
+    }
+}
+
+/// How a client proves who it is.
+pub enum Proof {
+    Password(String),
+    /// RFC 2195 keyed MD5 digest of `challenge`, in hex.
+    CramMd5 {
+        challenge: String,
+        digest: String,
+    },
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Credentials {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, secret)) if !user.is_empty() => {
+                    let secret = Secret::parse(secret).ok_or_else(|| {
+                        format!("line {}: unknown password scheme", i + 1)
+                    })?;
+                    users.insert(user.to_string(), secret);
+                }
+                _ => {
+                    return Err(format!("line {}: expected user:secret", i + 1))
+                }
+            }
+        }
+        Ok(Self { users })
+    }
+
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|text| Self::parse(&text))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// SASL mechanisms that can be checked against these credentials.
+    /// CRAM-MD5 needs every password in plain text.
+    pub fn mechanisms(&self) -> Vec<&'static str> {
+        let mut mechanisms = vec!["PLAIN", "LOGIN"];
+        let plain = |s: &Secret| matches!(s, Secret::Plain(_));
+        if !self.users.is_empty() && self.users.values().all(plain) {
+            mechanisms.push("CRAM-MD5");
+        }
+        mechanisms
+    }
+
+    /// Whether `proof` is right for `user`. Checking a bcrypt hash takes a
+    /// while on purpose, so async callers should not do it on the runtime.
+    pub fn check(&self, user: &str, proof: &Proof) -> bool {
+        match (self.users.get(user), proof) {
+            (Some(Secret::Plain(secret)), Proof::Password(password)) => {
+                secret.as_bytes().ct_eq(password.as_bytes()).into()
+            }
+            (Some(Secret::Bcrypt(hash)), Proof::Password(password)) => {
+                bcrypt::verify(password, hash).unwrap_or(false)
+            }
+            (
+                Some(Secret::Plain(secret)),
+                Proof::CramMd5 { challenge, digest },
+            ) => {
+                let Some(digest) = decode_hex(digest) else {
+                    return false;
+                };
+                let mut mac = Hmac::<Md5>::new_from_slice(secret.as_bytes())
+                    .expect("HMAC takes keys of any length");
+                mac.update(challenge.as_bytes());
+                mac.verify_slice(&digest).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A unique `<pid.counter.time@host>` challenge, as RFC 2195 suggests.
+fn cram_md5_challenge() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "<{}.{}.{}@localhost>",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        now.as_nanos()
+    )
+}
+
+/// The server side of an AUTH exchange (RFC 4422), waiting for the next
+/// response of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaslServer {
+    Plain,
+    LoginUser,
+    /// LOGIN after the user name.
+    LoginPassword(String),
+    /// CRAM-MD5 after sending the challenge.
+    CramMd5(String),
+}
+
+/// What the server does after a client response.
+pub enum SaslStep {
+    /// Send the challenge, base64 encoded, and wait for the next response.
+    Challenge(SaslServer, Vec<u8>),
+    /// The client claims to be the user and sent the proof.
+    Done(String, Proof),
+    Failed(&'static str),
+}
+
+impl SaslServer {
+    /// Starts `mechanism` with the initial response of the client, if it
+    /// sent one. `=` stands for an empty initial response.
+    pub fn start(mechanism: &str, initial: Option<&str>) -> SaslStep {
+        let state = match mechanism.to_ascii_uppercase().as_str() {
+            "PLAIN" => SaslServer::Plain,
+            "LOGIN" => SaslServer::LoginUser,
+            "CRAM-MD5" if initial.is_none() => {
+                let challenge = cram_md5_challenge();
+                let data = challenge.clone().into_bytes();
+                return SaslStep::Challenge(
+                    SaslServer::CramMd5(challenge),
+                    data,
+                );
+            }
+            "CRAM-MD5" => {
+                return SaslStep::Failed("unexpected initial response")
+            }
+            _ => return SaslStep::Failed("unsupported mechanism"),
+        };
+        match (initial, &state) {
+            (Some("="), _) => state.step(""),
+            (Some(response), _) => state.step(response),
+            (None, SaslServer::LoginUser) => {
+                SaslStep::Challenge(state, b"Username:".to_vec())
+            }
+            (None, _) => SaslStep::Challenge(state, Vec::new()),
+        }
+    }
+
+    /// Continues with a base64 response line. `*` cancels the exchange.
+    pub fn step(self, response: &str) -> SaslStep {
+        let response = response.trim_end();
+        if response == "*" {
+            return SaslStep::Failed("authentication cancelled");
+        }
+        let data = match base64::decode(response).map(String::from_utf8) {
+            Ok(Ok(data)) => data,
+            _ => return SaslStep::Failed("malformed response"),
+        };
+        match self {
+            SaslServer::Plain => {
+                let parts: Vec<&str> = data.split('\0').collect();
+                match parts[..] {
+                    [authz, user, password]
+                        if authz.is_empty() || authz == user =>
+                    {
+                        SaslStep::Done(
+                            user.to_string(),
+                            Proof::Password(password.to_string()),
+                        )
+                    }
+                    _ => SaslStep::Failed("malformed response"),
+                }
+            }
+            SaslServer::LoginUser => SaslStep::Challenge(
+                SaslServer::LoginPassword(data),
+                b"Password:".to_vec(),
+            ),
+            SaslServer::LoginPassword(user) => {
+                SaslStep::Done(user, Proof::Password(data))
+            }
+            SaslServer::CramMd5(challenge) => match data.rsplit_once(' ') {
+                Some((user, digest)) => SaslStep::Done(
+                    user.to_string(),
+                    Proof::CramMd5 {
+                        challenge,
+                        digest: digest.to_string(),
+                    },
+                ),
+                None => SaslStep::Failed("malformed response"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs an exchange, answering challenges from `responses`.
+    fn exchange(
+        mechanism: &str,
+        initial: Option<&str>,
+        responses: &[&str],
+    ) -> SaslStep {
+        let mut step = SaslServer::start(mechanism, initial);
+        for response in responses {
+            step = match step {
+                SaslStep::Challenge(state, _) => state.step(response),
+                step => return step,
+            };
+        }
+        step
+    }
+
+    #[test]
+    fn parse_and_check() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let credentials = Credentials::parse(&format!(
+            "# users\n\nalice:{{PLAIN}}wonder:land\r\nbob:{}\n",
+            hash
+        ))
+        .unwrap();
+        let password = |p: &str| Proof::Password(p.to_string());
+        assert!(credentials.check("alice", &password("wonder:land")));
+        assert!(!credentials.check("alice", &password("wonder")));
+        assert!(credentials.check("bob", &password("hunter2")));
+        assert!(!credentials.check("bob", &password(&hash)));
+        assert!(!credentials.check("carol", &password("")));
+        assert_eq!(credentials.mechanisms(), ["PLAIN", "LOGIN"]);
+        assert!(!format!("{:?}", credentials).contains("wonder"));
+
+        assert_eq!(
+            Credentials::parse("alice:wonderland").unwrap_err(),
+            "line 1: unknown password scheme"
+        );
+        assert_eq!(
+            Credentials::parse("\nalice").unwrap_err(),
+            "line 2: expected user:secret"
+        );
+    }
+
+    #[test]
+    fn sasl_exchanges() {
+        let credentials =
+            Credentials::parse("tim:{PLAIN}tanstaaftanstaaf").unwrap();
+        assert_eq!(credentials.mechanisms(), ["PLAIN", "LOGIN", "CRAM-MD5"]);
+        let accepted = |step: SaslStep| match step {
+            SaslStep::Done(user, proof) => credentials.check(&user, &proof),
+            _ => false,
+        };
+
+        let plain = base64::encode("\0tim\0tanstaaftanstaaf");
+        assert!(accepted(exchange("plain", Some(&plain), &[])));
+        assert!(accepted(exchange("PLAIN", None, &[&plain])));
+        let other = base64::encode("root\0tim\0tanstaaftanstaaf");
+        assert!(!accepted(exchange("PLAIN", Some(&other), &[])));
+
+        let (user, password) = (base64::encode("tim"), base64::encode("x"));
+        assert!(!accepted(exchange("LOGIN", None, &[&user, &password])));
+        let password = base64::encode("tanstaaftanstaaf");
+        assert!(accepted(exchange("LOGIN", None, &[&user, &password])));
+        assert!(matches!(
+            exchange("LOGIN", None, &[&user, "*"]),
+            SaslStep::Failed(_)
+        ));
+
+        // The example from RFC 2195, with its challenge.
+        let step = SaslServer::CramMd5(String::from(
+            "<1896.697170952@postoffice.reston.mci.net>",
+        ))
+        .step("dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw");
+        assert!(accepted(step));
+        let SaslStep::Challenge(_, challenge) = exchange("CRAM-MD5", None, &[])
+        else {
+            panic!("no challenge");
+        };
+        assert!(challenge.starts_with(b"<") && challenge.ends_with(b">"));
+        assert!(matches!(
+            exchange("CRAM-MD5", Some("="), &[]),
+            SaslStep::Failed(_)
+        ));
+        assert!(matches!(exchange("GSSAPI", None, &[]), SaslStep::Failed(_)));
+        assert!(matches!(
+            exchange("PLAIN", Some("not base64!"), &[]),
+            SaslStep::Failed(_)
+        ));
+    }
+}
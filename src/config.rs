@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tokio::{fs, io};
+
+use crate::tls::{TlsMode, TlsVerify};
+
+/// Used when `EMAIL_CONFIG` is not set.
+pub const DEFAULT_CONFIG: &str = "/etc/email/config.json";
+
+/// Where the POP3 server finds a user's mail. `{user}` in the path is
+/// replaced by the login name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum MailStore {
+    Mbox { path: String },
+    Maildir { path: String },
+}
+
+impl Default for MailStore {
+    fn default() -> Self {
+        MailStore::Mbox {
+            path: String::from("/var/mail/{user}"),
+        }
+    }
+}
+
+impl MailStore {
+    /// The mbox file or Maildir of `user`.
+    pub fn path_for(&self, user: &str) -> PathBuf {
+        let (MailStore::Mbox { path } | MailStore::Maildir { path }) = self;
+        PathBuf::from(path.replace("{user}", user))
+    }
+}
+
+/// Certificate of the POP3 server. With it, STLS is offered on the plain
+/// listener and POP3S is served on `listen`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTls {
+    /// PEM file with the certificate chain.
+    pub cert: String,
+    /// PEM file with the private key.
+    pub key: String,
+    /// Address of the implicit TLS listener.
+    #[serde(default = "default_tls_listen")]
+    pub listen: String,
+    /// Refuse USER and PASS until the connection is encrypted.
+    #[serde(default)]
+    pub require: bool,
+}
+
+fn default_tls_listen() -> String {
+    String::from("0.0.0.0:995")
+}
+
+/// Settings of the POP3 server, read from a JSON file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub store: MailStore,
+    pub tls: Option<ServerTls>,
+    /// File of `user:secret` lines checked by PASS and AUTH. Without it,
+    /// USER alone logs in.
+    pub passwd: Option<String>,
+    /// Seconds a session may stay idle before it is closed.
+    pub timeout: u64,
+    /// Sessions open at once, in total and from a single address.
+    pub max_sessions: usize,
+    pub max_sessions_per_ip: usize,
+    /// Seconds open sessions get to finish once the server is stopping.
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: String::from("0.0.0.0:110"),
+            store: MailStore::default(),
+            tls: None,
+            passwd: None,
+            // The minimum RFC 1939 allows.
+            timeout: 600,
+            max_sessions: 256,
+            max_sessions_per_ip: 16,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn parse(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    /// Reads the configuration at `path`. A missing file gives the
+    /// defaults.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        match fs::read_to_string(path).await {
+            Ok(json) => Self::parse(&json)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Settings of `email fetch`, read from a JSON file.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// POP3 server as `host:port`.
+    pub host: String,
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+    /// `"none"`, `"implicit"` or `"starttls"`.
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// PEM file of CA certificates to trust instead of the usual roots.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Skip certificate verification altogether.
+    #[serde(default)]
+    pub insecure: bool,
+    /// Local mbox or Maildir the messages are stored in. `{user}` is
+    /// replaced by `user`.
+    pub store: MailStore,
+    /// File recording the UIDLs of messages already fetched.
+    pub state: String,
+    /// Delete messages from the server once they are stored.
+    #[serde(default)]
+    pub delete: bool,
+}
+
+impl std::fmt::Debug for FetchConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FetchConfig")
+            .field("host", &self.host)
+            .field("user", &self.user)
+            .field("tls", &self.tls)
+            .field("ca_file", &self.ca_file)
+            .field("insecure", &self.insecure)
+            .field("store", &self.store)
+            .field("state", &self.state)
+            .field("delete", &self.delete)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FetchConfig {
+    /// How the server certificate is checked.
+    pub fn verify(&self) -> TlsVerify {
+        match &self.ca_file {
+            _ if self.insecure => TlsVerify::Insecure,
+            Some(path) => TlsVerify::CaFile(path.into()),
+            None => TlsVerify::WebPki,
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|json| Self::parse(&json))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(ServerConfig::parse("{}").unwrap(), ServerConfig::default());
+        let config = ServerConfig::parse(
+            r#"{"store": {"format": "maildir", "path": "/home/{user}/Maildir"}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:110");
+        assert_eq!(
+            config.store.path_for("root"),
+            PathBuf::from("/home/root/Maildir")
+        );
+        assert!(matches!(config.store, MailStore::Maildir { .. }));
+        assert_eq!(config.tls, None);
+        assert_eq!(config.timeout, 600);
+        assert_eq!(config.shutdown_timeout, 30);
+
+        let config = ServerConfig::parse(
+            r#"{
+                "tls": {"cert": "/etc/email/cert.pem", "key": "/etc/email/key.pem"},
+                "passwd": "/etc/email/passwd"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.passwd.as_deref(), Some("/etc/email/passwd"));
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.listen, "0.0.0.0:995");
+        assert!(!tls.require);
+        assert!(ServerConfig::parse(r#"{"tls": {"cert": "c.pem"}}"#).is_err());
+        assert!(ServerConfig::parse(r#"{"lisen": ":110"}"#).is_err());
+        assert!(ServerConfig::parse(r#"{"store": {"format": "mh"}}"#).is_err());
+    }
+
+    #[test]
+    fn parse_fetch_config() {
+        let config = FetchConfig::parse(
+            r#"{
+                "host": "pop.qq.com:110",
+                "user": "monitor",
+                "password": "secret",
+                "tls": "starttls",
+                "store": {"format": "mbox", "path": "/srv/{user}.mbox"},
+                "state": "/srv/monitor.uidl"
+            }"#,
+        )
+        .unwrap();
+        assert!(!config.delete);
+        assert_eq!(config.tls, TlsMode::StartTls);
+        assert_eq!(config.verify(), TlsVerify::WebPki);
+        assert_eq!(
+            config.store.path_for(&config.user),
+            PathBuf::from("/srv/monitor.mbox")
+        );
+        assert!(!format!("{:?}", config).contains("secret"));
+        assert!(FetchConfig::parse(r#"{"host": "x:110"}"#).is_err());
+    }
+}
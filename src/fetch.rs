@@ -0,0 +1,335 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncWriteExt},
+};
+
+use crate::{
+    config::{FetchConfig, MailStore},
+    maildir::Maildir,
+    mbox::{MboxFormat, MboxMessage, MboxWriter},
+    pop3::{Pop3Builder, Pop3Client},
+};
+
+/// UIDLs of messages already fetched, kept one per line in a state file.
+#[derive(Debug)]
+pub struct SeenUidls {
+    path: PathBuf,
+    seen: HashSet<String>,
+}
+
+impl SeenUidls {
+    /// Reads the state file at `path`. A missing file means nothing has
+    /// been fetched yet.
+    pub async fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let seen = match fs::read_to_string(&path).await {
+            Ok(s) => s.lines().map(String::from).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, seen })
+    }
+
+    pub fn contains(&self, uid: &str) -> bool {
+        self.seen.contains(uid)
+    }
+
+    /// Records a fetched message. The file is appended to right away, so an
+    /// interrupted fetch does not store the message twice.
+    pub async fn insert(&mut self, uid: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(format!("{}\n", uid).as_bytes()).await?;
+        file.sync_data().await?;
+        self.seen.insert(uid.to_string());
+        Ok(())
+    }
+
+    /// Forgets messages that are no longer on the server and rewrites the
+    /// state file.
+    pub async fn retain(
+        &mut self,
+        on_server: &HashSet<&str>,
+    ) -> io::Result<()> {
+        self.seen.retain(|uid| on_server.contains(uid.as_str()));
+        let mut uids: Vec<&str> =
+            self.seen.iter().map(String::as_str).collect();
+        uids.sort_unstable();
+        let mut data = uids.join("\n");
+        if !data.is_empty() {
+            data.push('\n');
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data).await?;
+        fs::rename(&tmp, &self.path).await
+    }
+}
+
+/// Local destination of fetched messages.
+enum Store {
+    Mbox(MboxWriter),
+    Maildir(Maildir),
+}
+
+impl Store {
+    async fn open(store: &MailStore, path: &Path) -> io::Result<Self> {
+        Ok(match store {
+            MailStore::Mbox { .. } => {
+                Store::Mbox(MboxWriter::open(path, MboxFormat::Mboxrd).await?)
+            }
+            MailStore::Maildir { .. } => {
+                let maildir = Maildir::new(path);
+                maildir.create().await?;
+                Store::Maildir(maildir)
+            }
+        })
+    }
+
+    async fn store(&mut self, data: Vec<u8>) -> io::Result<()> {
+        match self {
+            Store::Mbox(writer) => {
+                writer.append(&MboxMessage::from_message(data)).await
+            }
+            Store::Maildir(maildir) => maildir.deliver(&data).await.map(|_| ()),
+        }
+    }
+}
+
+/// Outcome of a fetch.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FetchReport {
+    /// Messages on the server according to STAT.
+    pub total: usize,
+    pub fetched: usize,
+    pub deleted: usize,
+}
+
+/// Logs in to the configured server and stores every message whose UIDL
+/// has not been seen before, deleting it afterwards if configured.
+pub async fn fetch(config: &FetchConfig) -> Result<FetchReport, ()> {
+    let mut pop = Pop3Builder::new()
+        .email(&config.user)
+        .password(&config.password)
+        .host(&config.host)
+        .tls(config.tls)
+        .verify(config.verify())
+        .build()
+        .await;
+    let report = fetch_with(&mut pop, config).await;
+    // Deletions only take effect once the session ends with QUIT.
+    if let Err(e) = pop.quit().await {
+        eprintln!("{}", e);
+    }
+    report
+}
+
+async fn fetch_with(
+    pop: &mut Pop3Client,
+    config: &FetchConfig,
+) -> Result<FetchReport, ()> {
+    let mut seen = SeenUidls::load(&config.state)
+        .await
+        .map_err(|e| eprintln!("{}: {}", config.state, e))?;
+    let path = config.store.path_for(&config.user);
+    let mut store = Store::open(&config.store, &path)
+        .await
+        .map_err(|e| eprintln!("{}: {}", path.display(), e))?;
+
+    let stat = pop.stat().await.map_err(|e| eprintln!("{}", e))?;
+    let uidl = pop.uidl().await.map_err(|e| eprintln!("{}", e))?;
+
+    let mut report = FetchReport {
+        total: stat.count,
+        ..Default::default()
+    };
+    for entry in &uidl {
+        if !seen.contains(&entry.uid) {
+            let data = pop
+                .retr(entry.number)
+                .await
+                .map_err(|e| eprintln!("message {}: {}", entry.number, e))?;
+            store.store(data).await.map_err(|e| eprintln!("{}", e))?;
+            seen.insert(&entry.uid)
+                .await
+                .map_err(|e| eprintln!("{}", e))?;
+            report.fetched += 1;
+        }
+        // A message seen before is still here if the session that fetched
+        // it ended without QUIT, which is what commits a deletion.
+        if config.delete {
+            match pop.dele(entry.number).await {
+                Ok(()) => report.deleted += 1,
+                Err(e) => eprintln!("message {}: {}", entry.number, e),
+            }
+        }
+    }
+
+    let on_server: HashSet<&str> =
+        uidl.iter().map(|e| e.uid.as_str()).collect();
+    seen.retain(&on_server)
+        .await
+        .map_err(|e| eprintln!("{}: {}", config.state, e))?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        pop3::{pop3_handler, Pop3UserState},
+        tls::{MaybeTls, TlsMode},
+    };
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    const MESSAGES: [&str; 2] = [
+        "From: a@example.com\r\nSubject: a\r\n\r\n..dot\r\n",
+        "From: b@example.com\r\nSubject: b\r\n\r\nbody\r\n",
+    ];
+
+    /// A POP3 server answering just what a fetch needs.
+    async fn serve(listener: TcpListener) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (r, mut w) = tokio::io::split(stream);
+            let mut r = BufReader::new(r);
+            w.write_all(b"+OK ready\r\n").await.unwrap();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if r.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let reply = match line.split_whitespace().collect::<Vec<_>>()[..]
+                {
+                    ["CAPA"] => String::from("-ERR unknown command\r\n"),
+                    ["STAT"] => String::from("+OK 2 96\r\n"),
+                    ["UIDL"] => {
+                        String::from("+OK\r\n1 uid-a\r\n2 uid-b\r\n.\r\n")
+                    }
+                    ["RETR", n] => {
+                        let m = MESSAGES[n.parse::<usize>().unwrap() - 1];
+                        format!("+OK\r\n{}.\r\n", m)
+                    }
+                    ["DELE", "1" | "2"] => String::from("+OK deleted\r\n"),
+                    ["DELE", _] => String::from("-ERR no such message\r\n"),
+                    _ => String::from("+OK\r\n"),
+                };
+                w.write_all(reply.as_bytes()).await.unwrap();
+                if line.starts_with("QUIT") {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn seen_uidls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let mut seen = SeenUidls::load(&path).await.unwrap();
+        seen.insert("a").await.unwrap();
+        seen.insert("b").await.unwrap();
+        let seen = SeenUidls::load(&path).await.unwrap();
+        assert!(seen.contains("a") && seen.contains("b"));
+
+        let mut seen = seen;
+        seen.retain(&HashSet::from(["b", "c"])).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "b\n");
+    }
+
+    #[tokio::test]
+    async fn fetch_into_maildir() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener));
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = FetchConfig {
+            host,
+            user: String::from("monitor"),
+            password: String::from("secret"),
+            tls: TlsMode::None,
+            ca_file: None,
+            insecure: false,
+            store: MailStore::Maildir {
+                path: dir.path().join("{user}").display().to_string(),
+            },
+            state: dir.path().join("state").display().to_string(),
+            delete: true,
+        };
+        let report = fetch(&config).await.unwrap();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.fetched, 2);
+        assert_eq!(report.deleted, 2);
+
+        let maildir = Maildir::new(dir.path().join("monitor"));
+        let entries = maildir.list().await.unwrap();
+        assert_eq!(entries.len(), 2);
+        let first = maildir.read(&entries[0]).await.unwrap();
+        assert_eq!(first, MESSAGES[0].replace("..dot", ".dot").as_bytes());
+
+        // Fetching again finds nothing new, but deletes what is still on
+        // the server.
+        let report = fetch(&config).await.unwrap();
+        assert_eq!(report.fetched, 0);
+        assert_eq!(report.deleted, 2);
+        assert_eq!(maildir.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_from_own_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let served = Maildir::new(dir.path().join("served"));
+        served.create().await.unwrap();
+        for m in MESSAGES {
+            served.deliver(m.as_bytes()).await.unwrap();
+        }
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let state = Pop3UserState::with_store(store.clone());
+                let idle = Duration::from_secs(60);
+                tokio::spawn(pop3_handler(MaybeTls::Plain(tcp), state, idle));
+            }
+        });
+
+        let config = FetchConfig {
+            host,
+            user: String::from("served"),
+            password: String::new(),
+            tls: TlsMode::None,
+            ca_file: None,
+            insecure: false,
+            store: MailStore::Maildir {
+                path: dir.path().join("fetched").display().to_string(),
+            },
+            state: dir.path().join("state").display().to_string(),
+            delete: false,
+        };
+        let report = fetch(&config).await.unwrap();
+        assert_eq!((report.total, report.fetched), (2, 2));
+        let fetched = Maildir::new(dir.path().join("fetched"));
+        assert_eq!(fetched.list().await.unwrap().len(), 2);
+
+        // Retrieving marked the messages seen, which keeps their UIDLs.
+        let report = fetch(&config).await.unwrap();
+        assert_eq!((report.total, report.fetched), (2, 0));
+    }
+}
@@ -1,3 +1,14 @@
+pub mod address;
+pub mod auth;
+pub mod config;
+pub mod fetch;
+pub mod limit;
+pub mod mailbox;
+pub mod maildir;
+pub mod mbox;
+pub mod message;
 pub mod mime;
 pub mod pop3;
 pub mod smtp;
+pub mod threading;
+pub mod tls;
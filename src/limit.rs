@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Caps the number of concurrent sessions, in total and per client address.
+#[derive(Debug)]
+pub struct SessionLimits {
+    max: AtomicUsize,
+    max_per_ip: AtomicUsize,
+    open: Mutex<Open>,
+    /// Woken when the last session closes.
+    closed: Notify,
+}
+
+/// A counted session, released when dropped.
+#[derive(Debug)]
+pub struct Session {
+    limits: Arc<SessionLimits>,
+    ip: IpAddr,
+}
+
+impl SessionLimits {
+    pub fn new(max: usize, max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max: AtomicUsize::new(max),
+            max_per_ip: AtomicUsize::new(max_per_ip),
+            open: Mutex::new(Open::default()),
+            closed: Notify::new(),
+        })
+    }
+
+    /// Changes the limits. Sessions already open are kept.
+    pub fn resize(&self, max: usize, max_per_ip: usize) {
+        self.max.store(max, Ordering::Relaxed);
+        self.max_per_ip.store(max_per_ip, Ordering::Relaxed);
+    }
+
+    /// Counts a session from `ip`, unless a limit has been reached.
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Result<Session, &'static str> {
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.max.load(Ordering::Relaxed) {
+            return Err("too many connections");
+        }
+        let from_ip = open.per_ip.entry(ip).or_default();
+        if *from_ip >= self.max_per_ip.load(Ordering::Relaxed) {
+            return Err("too many connections from your address");
+        }
+        *from_ip += 1;
+        open.total += 1;
+        Ok(Session {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    /// Sessions currently open.
+    pub fn count(&self) -> usize {
+        self.open.lock().unwrap().total
+    }
+
+    /// Waits until no session is open.
+    pub async fn closed(&self) {
+        loop {
+            // Created before checking, so a wakeup in between is not lost.
+            let closed = self.closed.notified();
+            if self.count() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        open.total -= 1;
+        if open.total == 0 {
+            self.limits.closed.notify_waiters();
+        }
+        if let Some(n) = open.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits() {
+        let limits = SessionLimits::new(3, 2);
+        let (a, b): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let a1 = limits.open(a).unwrap();
+        let a2 = limits.open(a).unwrap();
+        assert!(limits.open(a).is_err());
+        let b1 = limits.open(b).unwrap();
+        assert_eq!(limits.open(b).unwrap_err(), "too many connections");
+        assert_eq!(limits.count(), 3);
+
+        drop(a1);
+        assert_eq!(limits.count(), 2);
+        let a3 = limits.open(a).unwrap();
+        assert!(limits.open(b).is_err());
+
+        limits.resize(4, 2);
+        let b2 = limits.open(b).unwrap();
+        let closing = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.closed().await }
+        });
+        tokio::task::yield_now().await;
+        drop((a3, b2));
+        tokio::task::yield_now().await;
+        assert!(!closing.is_finished());
+        drop((a2, b1));
+        closing.await.unwrap();
+    }
+}
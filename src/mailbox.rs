@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use md5::{Digest, Md5};
+use tokio::{
+    fs::{self, File},
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt,
+        BufReader,
+    },
+};
+
+use crate::{
+    config::MailStore,
+    maildir::{Maildir, MaildirEntry},
+    mbox::{open_mbox, unquote_line, MboxFormat},
+    message::Message,
+};
+
+/// How the server reads mbox files.
+const MBOX_FORMAT: MboxFormat = MboxFormat::Mboxo;
+
+/// Longest header section read from a Maildir message when indexing.
+const MAX_HEADERS: u64 = 64 * 1024;
+
+/// Where a message is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Location {
+    /// Bytes of the message in the mbox file, quoting included.
+    Mbox {
+        offset: u64,
+        len: u64,
+    },
+    Maildir(MaildirEntry),
+}
+
+/// A message of a mailbox, without its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxEntry {
+    location: Location,
+    /// Size of the message as RETR sends it, in CRLF lines with leading
+    /// dots doubled (RFC 1939).
+    pub size: u64,
+    /// Unique id for UIDL (RFC 1939 section 7), kept across sessions.
+    pub uid: String,
+    pub from: String,
+    pub date: String,
+    pub subject: String,
+}
+
+/// Modification times and lengths of the files an index was built from.
+type Stamp = Vec<(SystemTime, u64)>;
+
+type Index = Arc<Vec<MailboxEntry>>;
+
+/// Indexes of the mailboxes opened so far, by path.
+fn cache() -> &'static Mutex<HashMap<PathBuf, (Stamp, Index)>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, (Stamp, Index)>>> =
+        OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+async fn stamp(store: &MailStore, path: &Path) -> io::Result<Stamp> {
+    let paths = match store {
+        MailStore::Mbox { .. } => vec![path.to_path_buf()],
+        MailStore::Maildir { .. } => vec![path.join("new"), path.join("cur")],
+    };
+    let mut stamp = Vec::new();
+    for path in paths {
+        let meta = fs::metadata(&path).await?;
+        stamp.push((meta.modified()?, meta.len()));
+    }
+    Ok(stamp)
+}
+
+/// Appends `line` as POP3 sends it: ending in CRLF, with a leading dot
+/// doubled.
+fn push_wire_line(wire: &mut Vec<u8>, line: &[u8]) {
+    let text = line.strip_suffix(b"\n").unwrap_or(line);
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+    if text.starts_with(b".") {
+        wire.push(b'.');
+    }
+    wire.extend_from_slice(text);
+    wire.extend_from_slice(b"\r\n");
+}
+
+/// Length of `line` as POP3 sends it.
+fn wire_len(line: &[u8]) -> u64 {
+    let text = line.strip_suffix(b"\n").unwrap_or(line);
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+    text.len() as u64 + 2 + u64::from(text.starts_with(b"."))
+}
+
+/// `data` as the body of a POP3 multi-line response, without the
+/// terminating dot.
+pub fn to_wire(data: &[u8]) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(data.len() + data.len() / 32);
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        push_wire_line(&mut wire, line);
+    }
+    wire
+}
+
+/// Calls `f` with each line of the header section and then `body_lines`
+/// lines of the body, or all of it. Mbox quoting is undone with `unquote`.
+async fn for_lines<R: AsyncBufRead + Unpin>(
+    mut r: R,
+    unquote: Option<MboxFormat>,
+    body_lines: Option<usize>,
+    mut f: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut in_headers = true;
+    let mut left = body_lines;
+    loop {
+        if !in_headers {
+            match left {
+                Some(0) => break,
+                Some(n) => left = Some(n - 1),
+                None => {}
+            }
+        }
+        line.clear();
+        if r.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        let line = match unquote {
+            Some(format) => unquote_line(format, &line),
+            None => &line[..],
+        };
+        f(line);
+        in_headers &= line != b"\n" && line != b"\r\n";
+    }
+    Ok(())
+}
+
+async fn read_lines<R: AsyncBufRead + Unpin>(
+    r: R,
+    unquote: Option<MboxFormat>,
+    body_lines: Option<usize>,
+) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for_lines(r, unquote, body_lines, |line| data.extend_from_slice(line))
+        .await?;
+    Ok(data)
+}
+
+async fn wire_size<R: AsyncBufRead + Unpin>(
+    r: R,
+    unquote: Option<MboxFormat>,
+) -> io::Result<u64> {
+    let mut size = 0;
+    for_lines(r, unquote, None, |line| size += wire_len(line)).await?;
+    Ok(size)
+}
+
+/// Hex MD5 of `data`, which fits the 70 printable octets a UIDL allows.
+fn uid(data: &[u8]) -> String {
+    Md5::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Sender, date and subject from a header section.
+fn summary(headers: &[u8]) -> (String, String, String) {
+    match Message::parse(headers) {
+        Ok(m) => (
+            m.from()
+                .iter()
+                .flat_map(|a| a.mailboxes())
+                .next()
+                .map(|m| m.addr_spec())
+                .unwrap_or_default(),
+            m.header("Date").unwrap_or_default().to_string(),
+            m.subject(),
+        ),
+        Err(_) => Default::default(),
+    }
+}
+
+async fn index_mbox(path: &Path) -> io::Result<Vec<MailboxEntry>> {
+    let mut reader = open_mbox(path, MBOX_FORMAT).await?;
+    let mut file = File::open(path).await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = reader.next_entry().await? {
+        let (_, _, subject) = summary(&entry.headers);
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+        let r = BufReader::new((&mut file).take(entry.len));
+        let size = wire_size(r, Some(MBOX_FORMAT)).await?;
+        // The headers tell apart messages at an offset the mbox was
+        // rewritten over.
+        let mut key = entry.offset.to_be_bytes().to_vec();
+        key.extend_from_slice(&entry.headers);
+        entries.push(MailboxEntry {
+            location: Location::Mbox {
+                offset: entry.offset,
+                len: entry.len,
+            },
+            size,
+            uid: uid(&key),
+            from: entry.sender,
+            date: entry.date,
+            subject,
+        });
+    }
+    Ok(entries)
+}
+
+async fn index_maildir(path: &Path) -> io::Result<Vec<MailboxEntry>> {
+    let mut entries = Vec::new();
+    for entry in Maildir::new(path).list().await? {
+        let mut file = File::open(&entry.path).await?;
+        let headers = read_lines(
+            BufReader::new((&mut file).take(MAX_HEADERS)),
+            None,
+            Some(0),
+        )
+        .await?;
+        file.rewind().await?;
+        let size = wire_size(BufReader::new(file), None).await?;
+        let (from, date, subject) = summary(&headers);
+        entries.push(MailboxEntry {
+            uid: uid(entry.id.as_bytes()),
+            location: Location::Maildir(entry),
+            size,
+            from,
+            date,
+            subject,
+        });
+    }
+    Ok(entries)
+}
+
+/// A user's mbox file or Maildir. Only an index is held in memory, and
+/// messages are read from disk when asked for.
+#[derive(Debug)]
+pub struct Mailbox {
+    path: PathBuf,
+    entries: Index,
+}
+
+impl Mailbox {
+    /// Opens the mailbox of `user`. It is indexed again only if it has
+    /// changed since it was last opened.
+    pub async fn open(store: &MailStore, user: &str) -> io::Result<Self> {
+        let path = store.path_for(user);
+        // Taken first, so a change made while indexing is noticed next time.
+        let stamp = stamp(store, &path).await?;
+        let cached = cache()
+            .lock()
+            .unwrap()
+            .get(&path)
+            .filter(|(s, _)| *s == stamp)
+            .map(|(_, entries)| entries.clone());
+        let entries = match cached {
+            Some(entries) => entries,
+            None => {
+                let entries = Arc::new(match store {
+                    MailStore::Mbox { .. } => index_mbox(&path).await?,
+                    MailStore::Maildir { .. } => index_maildir(&path).await?,
+                });
+                cache()
+                    .lock()
+                    .unwrap()
+                    .insert(path.clone(), (stamp, entries.clone()));
+                entries
+            }
+        };
+        Ok(Self { path, entries })
+    }
+
+    pub fn entries(&self) -> &[MailboxEntry] {
+        &self.entries
+    }
+
+    /// Message `i`, or only its headers and the first `body_lines` lines
+    /// of its body.
+    pub async fn read(
+        &self,
+        i: usize,
+        body_lines: Option<usize>,
+    ) -> io::Result<Vec<u8>> {
+        let entry = self.entries.get(i).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no such message")
+        })?;
+        match &entry.location {
+            Location::Mbox { offset, len } => {
+                let mut file = File::open(&self.path).await?;
+                file.seek(SeekFrom::Start(*offset)).await?;
+                let r = BufReader::new(file.take(*len));
+                read_lines(r, Some(MBOX_FORMAT), body_lines).await
+            }
+            Location::Maildir(entry) => {
+                let r = BufReader::new(File::open(&entry.path).await?);
+                read_lines(r, None, body_lines).await
+            }
+        }
+    }
+
+    /// Marks a retrieved message as no longer new. Only a Maildir keeps
+    /// the flag.
+    pub async fn mark_seen(&mut self, i: usize) -> io::Result<()> {
+        let Some(Location::Maildir(entry)) =
+            self.entries.get(i).map(|e| &e.location)
+        else {
+            return Ok(());
+        };
+        let seen = Maildir::new(&self.path).mark_seen(entry).await?;
+        Arc::make_mut(&mut self.entries)[i].location = Location::Maildir(seen);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mbox::{MboxMessage, MboxWriter};
+
+    #[tokio::test]
+    async fn mbox_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MailStore::Mbox {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let path = store.path_for("root");
+        let mut writer = MboxWriter::open(&path, MBOX_FORMAT).await.unwrap();
+        let first = b"Subject: one\n\nline 1\nFrom line 2\nline 3\n";
+        for data in [&first[..], b"Subject: \xff\n\n\xfe\n"] {
+            let m = MboxMessage::new("a@example.com", data.to_vec());
+            writer.append(&m).await.unwrap();
+        }
+
+        let mailbox = Mailbox::open(&store, "root").await.unwrap();
+        let entries = mailbox.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].subject, "one");
+        assert_eq!(entries[0].from, "a@example.com");
+        // Five bare LFs become CRLFs on the wire.
+        assert_eq!(entries[0].size, first.len() as u64 + 5);
+        assert_ne!(entries[0].uid, entries[1].uid);
+        assert_eq!(mailbox.read(0, None).await.unwrap(), first);
+        assert_eq!(
+            mailbox.read(0, Some(2)).await.unwrap(),
+            b"Subject: one\n\nline 1\nFrom line 2\n"
+        );
+        // Mail that is not UTF-8 is served as it is.
+        assert_eq!(
+            mailbox.read(1, None).await.unwrap(),
+            b"Subject: \xff\n\n\xfe\n"
+        );
+        assert!(mailbox.read(2, None).await.is_err());
+
+        // The index is reused until the file changes.
+        let again = Mailbox::open(&store, "root").await.unwrap();
+        assert!(Arc::ptr_eq(&mailbox.entries, &again.entries));
+        let m =
+            MboxMessage::new("b@example.com", b"Subject: three\n\n".to_vec());
+        writer.append(&m).await.unwrap();
+        let changed = Mailbox::open(&store, "root").await.unwrap();
+        assert_eq!(changed.entries().len(), 3);
+        assert_eq!(changed.entries()[..2], mailbox.entries()[..]);
+    }
+
+    #[tokio::test]
+    async fn maildir_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let maildir = Maildir::new(store.path_for("root"));
+        maildir.create().await.unwrap();
+        let data = b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n";
+        maildir.deliver(data).await.unwrap();
+
+        let mut mailbox = Mailbox::open(&store, "root").await.unwrap();
+        let entry = &mailbox.entries()[0];
+        assert_eq!(
+            (entry.from.as_str(), entry.subject.as_str()),
+            ("a@example.com", "hi")
+        );
+        assert_eq!(entry.size, data.len() as u64);
+        assert_eq!(mailbox.read(0, Some(0)).await.unwrap(), &data[..36]);
+        let uid = entry.uid.clone();
+        mailbox.mark_seen(0).await.unwrap();
+        assert_eq!(mailbox.read(0, None).await.unwrap(), data);
+        assert_eq!(maildir.list().await.unwrap()[0].flags, "S");
+        // Marking a message seen renames it, but keeps its UIDL.
+        let again = Mailbox::open(&store, "root").await.unwrap();
+        assert_eq!(again.entries()[0].uid, uid);
+    }
+
+    #[tokio::test]
+    async fn wire_sizes() {
+        let data = b"Subject: dots\n\n.\n..two\r\nbare\n.last";
+        let wire = b"Subject: dots\r\n\r\n..\r\n...two\r\nbare\r\n..last\r\n";
+        assert_eq!(to_wire(data), wire);
+        assert_eq!(to_wire(b""), b"");
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let maildir = Maildir::new(store.path_for("root"));
+        maildir.create().await.unwrap();
+        maildir.deliver(data).await.unwrap();
+        let mailbox = Mailbox::open(&store, "root").await.unwrap();
+        assert_eq!(mailbox.entries()[0].size, wire.len() as u64);
+
+        let store = MailStore::Mbox {
+            path: dir.path().join("{user}.mbox").display().to_string(),
+        };
+        let mut writer = MboxWriter::open(store.path_for("root"), MBOX_FORMAT)
+            .await
+            .unwrap();
+        let m = MboxMessage::new("a@example.com", b"\n.From x\n".to_vec());
+        writer.append(&m).await.unwrap();
+        writer.append(&m).await.unwrap();
+        let mailbox = Mailbox::open(&store, "root").await.unwrap();
+        for (i, entry) in mailbox.entries().iter().enumerate() {
+            let data = mailbox.read(i, None).await.unwrap();
+            assert_eq!(data, b"\n.From x\n");
+            assert_eq!(entry.size, to_wire(&data).len() as u64);
+        }
+    }
+}
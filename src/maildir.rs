@@ -0,0 +1,253 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncWriteExt},
+};
+
+/// Flags a message can carry in its `:2,` info suffix, in the order they
+/// are written.
+const FLAGS: &str = "DFPRST";
+
+/// A message stored in a Maildir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaildirEntry {
+    /// Unique name, without the info suffix.
+    pub id: String,
+    pub path: PathBuf,
+    /// Whether the message is still in `new`.
+    pub new: bool,
+    /// Flags from the `:2,` suffix, such as `S` for seen.
+    pub flags: String,
+}
+
+/// A Maildir with `tmp`, `new` and `cur` subdirectories.
+#[derive(Debug, Clone)]
+pub struct Maildir {
+    path: PathBuf,
+}
+
+/// Host part of unique names, with `/` and `:` escaped as the Maildir
+/// specification asks.
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| String::from("localhost"))
+            .replace('/', "\\057")
+            .replace(':', "\\072")
+    })
+}
+
+/// `time.MmicrosPpidQcounter.host`, unique across processes and deliveries
+/// within the same microsecond.
+fn unique_name() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{:06}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        hostname()
+    )
+}
+
+/// Valid flags of `flags`, sorted and without duplicates.
+fn normalize_flags(flags: &str) -> String {
+    FLAGS.chars().filter(|f| flags.contains(*f)).collect()
+}
+
+impl Maildir {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates the Maildir and its subdirectories if they do not exist.
+    pub async fn create(&self) -> io::Result<()> {
+        for dir in ["tmp", "new", "cur"] {
+            fs::create_dir_all(self.path.join(dir)).await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers a message to `new`. It is written and synced under `tmp`
+    /// first, so readers never see a partial message.
+    pub async fn deliver(&self, data: &[u8]) -> io::Result<MaildirEntry> {
+        let id = unique_name();
+        let tmp = self.path.join("tmp").join(&id);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .await?;
+        let written = async {
+            file.write_all(data).await?;
+            file.sync_all().await
+        }
+        .await;
+        if let Err(e) = written {
+            fs::remove_file(&tmp).await.ok();
+            return Err(e);
+        }
+        let path = self.path.join("new").join(&id);
+        if let Err(e) = fs::rename(&tmp, &path).await {
+            fs::remove_file(&tmp).await.ok();
+            return Err(e);
+        }
+        Ok(MaildirEntry {
+            id,
+            path,
+            new: true,
+            flags: String::new(),
+        })
+    }
+
+    /// Messages in `new` and then `cur`, each in delivery order. Dot files
+    /// are skipped.
+    pub async fn list(&self) -> io::Result<Vec<MaildirEntry>> {
+        let mut entries = Vec::new();
+        for (dir, new) in [("new", true), ("cur", false)] {
+            let mut found = Vec::new();
+            let mut rd = fs::read_dir(self.path.join(dir)).await?;
+            while let Some(e) = rd.next_entry().await? {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let (id, flags) = match name.split_once(":2,") {
+                    Some((id, flags)) => (id.to_string(), flags.to_string()),
+                    None => (name, String::new()),
+                };
+                found.push(MaildirEntry {
+                    id,
+                    path: e.path(),
+                    new,
+                    flags,
+                });
+            }
+            // Names start with the delivery time in seconds.
+            found.sort_by(|a, b| {
+                let secs = |e: &MaildirEntry| {
+                    let secs = e.id.split('.').next().unwrap_or_default();
+                    secs.parse::<u64>().unwrap_or(u64::MAX)
+                };
+                secs(a).cmp(&secs(b)).then_with(|| a.id.cmp(&b.id))
+            });
+            entries.append(&mut found);
+        }
+        Ok(entries)
+    }
+
+    pub async fn read(&self, entry: &MaildirEntry) -> io::Result<Vec<u8>> {
+        fs::read(&entry.path).await
+    }
+
+    /// Moves the message to `cur` with `flags`, which replace the old ones.
+    pub async fn set_flags(
+        &self,
+        entry: &MaildirEntry,
+        flags: &str,
+    ) -> io::Result<MaildirEntry> {
+        let flags = normalize_flags(flags);
+        let path = self
+            .path
+            .join("cur")
+            .join(format!("{}:2,{}", entry.id, flags));
+        if path != entry.path {
+            fs::rename(&entry.path, &path).await?;
+        }
+        Ok(MaildirEntry {
+            id: entry.id.clone(),
+            path,
+            new: false,
+            flags,
+        })
+    }
+
+    /// Moves a retrieved message to `cur` and marks it seen.
+    pub async fn mark_seen(
+        &self,
+        entry: &MaildirEntry,
+    ) -> io::Result<MaildirEntry> {
+        self.set_flags(entry, &format!("{}S", entry.flags)).await
+    }
+
+    pub async fn remove(&self, entry: &MaildirEntry) -> io::Result<()> {
+        fs::remove_file(&entry.path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn deliver_and_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::new(dir.path().join("Maildir"));
+        maildir.create().await.unwrap();
+        let a = maildir.deliver(b"Subject: a\r\n\r\n").await.unwrap();
+        let b = maildir.deliver(b"Subject: b\r\n\r\n").await.unwrap();
+        assert_ne!(a.id, b.id);
+        assert!(!a.id.contains(['/', ':']));
+        assert!(std::fs::read_dir(dir.path().join("Maildir/tmp"))
+            .unwrap()
+            .next()
+            .is_none());
+
+        let listed = maildir.list().await.unwrap();
+        assert_eq!(listed, [a.clone(), b]);
+        assert_eq!(maildir.read(&a).await.unwrap(), b"Subject: a\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn flags_and_cur() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::new(dir.path());
+        maildir.create().await.unwrap();
+        let a = maildir.deliver(b"a").await.unwrap();
+
+        let seen = maildir.mark_seen(&a).await.unwrap();
+        assert!(!seen.new);
+        assert!(!a.path.exists());
+        assert_eq!(
+            seen.path,
+            dir.path().join("cur").join(format!("{}:2,S", a.id))
+        );
+
+        let flagged = maildir.set_flags(&seen, "TSxFS").await.unwrap();
+        assert_eq!(flagged.flags, "FST");
+        let listed = maildir.list().await.unwrap();
+        assert_eq!(listed, [flagged.clone()]);
+        assert_eq!(maildir.mark_seen(&flagged).await.unwrap(), flagged);
+
+        std::fs::write(dir.path().join("cur/.hidden"), b"").unwrap();
+        std::fs::write(dir.path().join("new/1.M1P1Q1.host"), b"").unwrap();
+        let listed = maildir.list().await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].new && listed[0].flags.is_empty());
+
+        maildir.remove(&flagged).await.unwrap();
+        assert_eq!(maildir.list().await.unwrap().len(), 1);
+    }
+}
@@ -1,52 +1,207 @@
 #![allow(unused_must_use)]
-use std::{env, io::Write};
+use std::{env, io::Write, sync::Arc, time::Duration};
 
 use email::{
+    auth::Credentials,
+    config::{FetchConfig, ServerConfig, DEFAULT_CONFIG},
+    fetch::fetch,
+    limit::SessionLimits,
+    mbox::{MboxFormat, MboxMessage, MboxWriter},
+    message::Message,
     mime::{
-        parse_content_type, Alternative, ContentTransferEncoding, ContentType,
+        load_mime_types, Alternative, ContentTransferEncoding, ContentType,
     },
-    pop3::{pop3_handler_state, Pop3Builder, Pop3Command, Pop3UserState},
+    pop3::{pop3_handler, Pop3Builder, Pop3Client, Pop3Command, Pop3UserState},
     smtp::SmtpBuilder,
+    threading::{thread_messages, Thread},
+    tls::{accept, server_config, MaybeTls},
 };
 use tokio::{
-    io::{
-        self, stdin, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader,
-    },
-    net::{TcpListener, TcpStream},
+    io::{stdin, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::timeout,
 };
+use tokio_rustls::rustls;
 
+pub mod address;
+pub mod auth;
+pub mod config;
+pub mod fetch;
+pub mod limit;
+pub mod mailbox;
+pub mod maildir;
+pub mod mbox;
+pub mod message;
 pub mod mime;
 pub mod pop3;
 pub mod smtp;
+pub mod threading;
+pub mod tls;
 
 const CLEAR: &str = "\x1b[2J\x1b[H";
 
-pub async fn pop3_handler(mut stream: TcpStream) {
-    stream.write(b"+OK\r\n").await.unwrap();
-    let (mut r, mut w) = io::split(stream);
-    let mut state = Pop3UserState::new();
-    let mut buf = vec![0; 1024];
-    loop {
-        match r.read(&mut buf).await {
-            Ok(n) if n == 0 => {
-                println!("connection closed");
-                break;
+/// What the server reads at startup and again on SIGHUP.
+struct Settings {
+    config: Arc<ServerConfig>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    credentials: Option<Arc<Credentials>>,
+}
+
+impl Settings {
+    async fn load(path: &str) -> Result<Self, ()> {
+        let config = ServerConfig::load(path).await.map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        let tls = match &config.tls {
+            Some(t) => Some(server_config(&t.cert, &t.key).map_err(|e| {
+                eprintln!("{}", e);
+            })?),
+            None => None,
+        };
+        let credentials = match &config.passwd {
+            Some(path) => {
+                Some(Arc::new(Credentials::load(path).await.map_err(|e| {
+                    eprintln!("{}", e);
+                })?))
             }
-            Ok(n) => {
-                if let Err(_) =
-                    pop3_handler_state(&mut w, &mut state, &buf, n).await
-                {
-                    return;
-                }
+            None => None,
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            tls,
+            credentials,
+        })
+    }
+}
+
+/// Accepts POP3 connections, encrypted from the first byte when
+/// `implicit`, until `shutdown` turns true.
+async fn pop3_listen(
+    listener: TcpListener,
+    settings: watch::Receiver<Arc<Settings>>,
+    limits: Arc<SessionLimits>,
+    mut shutdown: watch::Receiver<bool>,
+    implicit: bool,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.changed() => break,
+        };
+        match accepted {
+            Ok((stream, addr)) => {
+                let settings = settings.borrow().clone();
+                let config = &settings.config;
+                let idle = Duration::from_secs(config.timeout);
+                let session = limits.open(addr.ip());
+                let mut state = Pop3UserState::with_store(config.store.clone());
+                state.require_tls =
+                    config.tls.as_ref().is_some_and(|t| t.require);
+                state.secure = implicit;
+                state.tls = settings.tls.clone();
+                state.credentials = settings.credentials.clone();
+                state.shutdown = Some(shutdown.clone());
+                tokio::task::spawn(async move {
+                    let mut stream = match &state.tls {
+                        Some(tls) if implicit => {
+                            match timeout(idle, accept(tls.clone(), stream))
+                                .await
+                            {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    eprintln!("{}", e);
+                                    return;
+                                }
+                                Err(_) => return,
+                            }
+                        }
+                        // TLS was dropped from the configuration.
+                        None if implicit => return,
+                        _ => MaybeTls::Plain(stream),
+                    };
+                    match session {
+                        Ok(session) => {
+                            pop3_handler(stream, state, idle).await;
+                            drop(session);
+                        }
+                        Err(e) => {
+                            let reply = format!("-ERR {}\r\n", e);
+                            stream.write_all(reply.as_bytes()).await.ok();
+                        }
+                    }
+                });
             }
             Err(e) => {
                 eprintln!("{}", e);
-                break;
             }
         }
     }
 }
 
+/// Runs the POP3 server until SIGTERM or SIGINT. SIGHUP reloads the
+/// configuration at `path` with its certificate and credentials.
+async fn serve(path: &str) -> Result<(), ()> {
+    let settings = Settings::load(path).await?;
+    let config = settings.config.clone();
+    let limits =
+        SessionLimits::new(config.max_sessions, config.max_sessions_per_ip);
+    let (reload, settings) = watch::channel(Arc::new(settings));
+    let (stop, shutdown) = watch::channel(false);
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+
+    let pop3_listner = TcpListener::bind(&config.listen).await.unwrap();
+    tokio::task::spawn(pop3_listen(
+        pop3_listner,
+        settings.clone(),
+        limits.clone(),
+        shutdown.clone(),
+        false,
+    ));
+    if let Some(t) = &config.tls {
+        let pop3s_listener = TcpListener::bind(&t.listen).await.unwrap();
+        tokio::task::spawn(pop3_listen(
+            pop3s_listener,
+            settings.clone(),
+            limits.clone(),
+            shutdown.clone(),
+            true,
+        ));
+    }
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+        let Ok(new) = Settings::load(path).await else {
+            eprintln!("keeping the previous configuration");
+            continue;
+        };
+        let listen = |c: &ServerConfig| {
+            (c.listen.clone(), c.tls.as_ref().map(|t| t.listen.clone()))
+        };
+        if listen(&new.config) != listen(&config) {
+            eprintln!("listen addresses change on restart");
+        }
+        limits.resize(new.config.max_sessions, new.config.max_sessions_per_ip);
+        reload.send_replace(Arc::new(new));
+        println!("configuration reloaded");
+    }
+
+    // Listeners stop accepting, and idle sessions end.
+    stop.send_replace(true);
+    let grace = Duration::from_secs(settings.borrow().config.shutdown_timeout);
+    if timeout(grace, limits.closed()).await.is_err() {
+        eprintln!("closing {} sessions still open", limits.count());
+    }
+    Ok(())
+}
+
 fn parse_args(args: Vec<String>) -> Result<i32, ()> {
     if args.len() == 3 {
         if args[1].eq("-s") && args[2].eq("start") {
@@ -57,9 +212,14 @@ fn parse_args(args: Vec<String>) -> Result<i32, ()> {
             } else if args[2].eq("recv") {
                 return Ok(2);
             }
+        } else if args[1].eq("fetch") {
+            return Ok(3);
         }
     }
-    println!("usage: email -s start to run server or email -t send/recv");
+    println!(
+        "usage: email -s start to run server, email -t send/recv \
+         or email fetch <config>"
+    );
     return Err(());
 }
 
@@ -103,8 +263,12 @@ async fn send() -> Result<(), ()> {
     attachment.push(Alternative {
         filename: None,
         content,
-        content_type: ContentType::TextHtml,
+        source: None,
+        charset: None,
+        content_type: ContentType::TextHtml.into(),
         encoding: ContentTransferEncoding::Base64,
+        disposition: None,
+        content_id: None,
     });
 
     print!("Attachment yes/no? ");
@@ -112,6 +276,7 @@ async fn send() -> Result<(), ()> {
     rd.read_line(&mut attach).await.unwrap();
 
     if attach.trim().to_lowercase().eq("yes") {
+        load_mime_types("/etc/mime.types").await;
         println!("Enter filepath per line, end with .<CR><CF>");
         std::io::stdout().flush();
         loop {
@@ -120,12 +285,10 @@ async fn send() -> Result<(), ()> {
             if file.ends_with(".\n") {
                 break;
             }
-            attachment.push(Alternative {
-                filename: Some(String::from(file.trim())),
-                content: "".to_string(),
-                content_type: parse_content_type(file.trim()),
-                encoding,
-            })
+            match Alternative::from_path(file.trim()).sniff().await {
+                Ok(alternative) => attachment.push(alternative),
+                Err(e) => eprintln!("{}: {}", file.trim(), e),
+            }
         }
     } else {
         println!("You choose send a mail with no attachment");
@@ -142,6 +305,7 @@ async fn send() -> Result<(), ()> {
             content_type,
             "",
             Some(&attachment),
+            None,
         )
         .await
     {
@@ -156,6 +320,83 @@ async fn send() -> Result<(), ()> {
     Ok(())
 }
 
+fn render_threads(
+    view: &mut String,
+    threads: &[Thread],
+    depth: usize,
+    numbers: &[i32],
+    messages: &[Message],
+) {
+    for t in threads {
+        let indent = match depth {
+            0 => String::new(),
+            _ => format!("{}└ ", "  ".repeat(depth - 1)),
+        };
+        match t.message {
+            Some(i) => {
+                let m = &messages[i];
+                let from = m
+                    .from()
+                    .first()
+                    .and_then(|a| a.mailboxes().first())
+                    .map(|m| m.name.clone().unwrap_or_else(|| m.addr_spec()))
+                    .unwrap_or_default();
+                view.push_str(&format!(
+                    "{:<4} {:<20}  {:<30}  {}{}\r\n",
+                    numbers[i],
+                    from,
+                    m.header("Date").unwrap_or_default(),
+                    indent,
+                    m.subject()
+                ));
+            }
+            None => view.push_str(&format!(
+                "{:<4} {:<20}  {:<30}  {}(missing)\r\n",
+                "-", "", "", indent
+            )),
+        }
+        render_threads(view, &t.children, depth + 1, numbers, messages);
+    }
+}
+
+/// Fetches the headers of every message and lays them out as conversations.
+async fn threaded_view(pop: &mut Pop3Client) -> Result<String, ()> {
+    let list = pop.cmd(Pop3Command::LIST).await?;
+    let mut numbers = Vec::new();
+    let mut messages = Vec::new();
+    for line in String::from_utf8_lossy(&list).lines() {
+        let n = match line.split_whitespace().next().map(str::parse) {
+            Some(Ok(n)) => n,
+            _ => continue,
+        };
+        let top = pop.cmd(Pop3Command::TOP(n, 0)).await?;
+        if let Ok(m) = Message::parse(&top) {
+            numbers.push(n);
+            messages.push(m);
+        }
+    }
+    let mut view =
+        format!("No   {:<20}  {:<30}  {:<15}\r\n", "From", "Time", "Subject");
+    let threads = thread_messages(&messages);
+    render_threads(&mut view, &threads, 0, &numbers, &messages);
+    Ok(view)
+}
+
+/// Appends message `n` to the mbox file at `path`.
+async fn save_message(
+    pop: &mut Pop3Client,
+    n: i32,
+    path: &str,
+) -> Result<(), ()> {
+    let data = pop.cmd(Pop3Command::RETR(n)).await?;
+    let message = MboxMessage::from_message(data);
+    let result = match MboxWriter::open(path, MboxFormat::Mboxrd).await {
+        Ok(mut writer) => writer.append(&message).await,
+        Err(e) => Err(e),
+    };
+    result.map_err(|e| eprintln!("{}: {}", path, e))
+}
+
 async fn recv() -> Result<(), ()> {
     let mut rd = BufReader::new(stdin());
     let mut username = String::new();
@@ -170,12 +411,13 @@ async fn recv() -> Result<(), ()> {
         .build()
         .await;
 
-    let mails = pop.cmd(Pop3Command::INFO).await.unwrap();
+    let mails = String::from_utf8_lossy(&pop.cmd(Pop3Command::INFO).await?)
+        .into_owned();
     print!("{}", mails);
     std::io::stdout().flush();
 
     loop {
-        print!("Enter mail ID to read detail# ");
+        print!("Enter mail ID to read detail, or thread# ");
         std::io::stdout().flush();
         let mut tmp_str = String::new();
         rd.read_line(&mut tmp_str).await.unwrap();
@@ -186,6 +428,27 @@ async fn recv() -> Result<(), ()> {
                 if ref_s.to_lowercase().starts_with("quit") {
                     println!("\nBye");
                     return Ok(());
+                } else if let Some(args) = ref_s.strip_prefix("save ") {
+                    let args =
+                        args.trim().split_once(' ').and_then(|(n, path)| {
+                            Some((n.parse().ok()?, path.trim()))
+                        });
+                    match args {
+                        Some((n, path)) => {
+                            if save_message(&mut pop, n, path).await.is_ok() {
+                                println!("saved to {}", path);
+                            }
+                        }
+                        None => eprintln!("usage: save <ID> <mbox path>"),
+                    }
+                    continue;
+                } else if ref_s.starts_with("thread") {
+                    match threaded_view(&mut pop).await {
+                        Ok(view) => print!("{}", view),
+                        Err(_) => eprintln!("failed to fetch headers"),
+                    }
+                    std::io::stdout().flush();
+                    continue;
                 } else if ref_s.starts_with("clear") {
                     print!("{}", CLEAR);
                     std::io::stdout().flush();
@@ -197,34 +460,34 @@ async fn recv() -> Result<(), ()> {
                 continue;
             }
         };
-        print!("{}", pop.cmd(Pop3Command::RETR(n)).await.unwrap());
+        if let Ok(mail) = pop.cmd(Pop3Command::RETR(n)).await {
+            print!("{}", String::from_utf8_lossy(&mail));
+        }
     }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), ()> {
     let args: Vec<String> = env::args().collect();
-    let rc = parse_args(args)?;
+    let rc = parse_args(args.clone())?;
 
     if rc == 0 {
-        let pop3_listner = TcpListener::bind(("0.0.0.0", 110)).await.unwrap();
-
-        loop {
-            match pop3_listner.accept().await {
-                Ok((stream, _)) => {
-                    tokio::task::spawn(async move {
-                        pop3_handler(stream).await;
-                    });
-                }
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
-            }
-        }
+        let path =
+            env::var("EMAIL_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG.into());
+        serve(&path).await?;
     } else if rc == 1 {
         send().await?;
     } else if rc == 2 {
         recv().await?;
+    } else if rc == 3 {
+        let config = FetchConfig::load(&args[2]).await.map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        let report = fetch(&config).await?;
+        println!(
+            "{} messages on server, {} fetched, {} deleted",
+            report.total, report.fetched, report.deleted
+        );
     } else {
         return Err(());
     }
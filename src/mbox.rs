@@ -0,0 +1,409 @@
+use std::path::Path;
+
+use chrono::Local;
+
+use crate::message::Message;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+};
+
+/// How body lines that look like separators are quoted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MboxFormat {
+    /// `From ` lines become `>From `. Reading cannot tell these apart from
+    /// lines that were `>From ` to begin with.
+    Mboxo,
+    /// Every `>*From ` line gets one more `>`, which reading undoes exactly.
+    Mboxrd,
+}
+
+/// One message of an mbox file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MboxMessage {
+    /// Envelope sender from the `From ` separator line.
+    pub sender: String,
+    /// Delivery time from the separator line, in asctime format.
+    pub date: String,
+    /// The message with line endings as stored and quoting undone.
+    pub data: Vec<u8>,
+}
+
+impl MboxMessage {
+    /// A message delivered now.
+    pub fn new(sender: &str, data: Vec<u8>) -> Self {
+        Self {
+            sender: sender.to_string(),
+            date: Local::now().format("%a %b %e %H:%M:%S %Y").to_string(),
+            data,
+        }
+    }
+
+    /// A message delivered now, with the envelope sender taken from its
+    /// From header.
+    pub fn from_message(data: Vec<u8>) -> Self {
+        let sender = Message::parse(&data)
+            .ok()
+            .and_then(|m| {
+                let from = m.from();
+                let mailbox = from.first()?.mailboxes().first()?;
+                Some(mailbox.addr_spec())
+            })
+            .unwrap_or_else(|| String::from("MAILER-DAEMON"));
+        Self::new(&sender, data)
+    }
+}
+
+/// Where a message lies in an mbox file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MboxEntry {
+    pub sender: String,
+    pub date: String,
+    /// Offset of the line after the separator.
+    pub offset: u64,
+    /// Length of the message as stored, quoting included.
+    pub len: u64,
+    /// Length of the message with quoting undone.
+    pub size: u64,
+    /// Header section with quoting undone, up to the blank line ending it.
+    pub headers: Vec<u8>,
+}
+
+/// Longest header section kept by [`MboxReader::next_entry`].
+const MAX_HEADERS: usize = 64 * 1024;
+
+/// Reads messages one at a time, holding only the current one in memory.
+pub struct MboxReader<R> {
+    reader: R,
+    format: MboxFormat,
+    /// Separator of the next message, already consumed.
+    next: Option<(String, String)>,
+    started: bool,
+    /// Bytes read so far.
+    pos: u64,
+}
+
+/// Opens an mbox file for reading.
+pub async fn open_mbox(
+    path: impl AsRef<Path>,
+    format: MboxFormat,
+) -> io::Result<MboxReader<BufReader<File>>> {
+    Ok(MboxReader::new(
+        BufReader::new(File::open(path).await?),
+        format,
+    ))
+}
+
+/// Sender and date of a `From sender date` separator line. The date must
+/// look like one, so that unquoted body text after a blank line, as found
+/// in mboxo files, is not taken for a separator.
+fn parse_separator(line: &[u8]) -> Option<(String, String)> {
+    let line = String::from_utf8_lossy(line);
+    let rest = line.strip_prefix("From ")?.trim();
+    let (sender, date) = rest.split_once([' ', '\t'])?;
+    let date = date.trim();
+    let digits = date.bytes().filter(|b| b.is_ascii_digit()).count();
+    if sender.is_empty() || !date.contains(':') || digits < 4 {
+        return None;
+    }
+    Some((sender.to_string(), date.to_string()))
+}
+
+/// Number of `>` before `From ` if the line is a quoted or unquoted
+/// separator look-alike.
+fn from_depth(line: &[u8]) -> Option<usize> {
+    let depth = line.iter().take_while(|b| **b == b'>').count();
+    line[depth..].starts_with(b"From ").then_some(depth)
+}
+
+/// `line` with the quoting of `format` undone.
+pub fn unquote_line(format: MboxFormat, line: &[u8]) -> &[u8] {
+    match (format, from_depth(line)) {
+        (MboxFormat::Mboxrd, Some(depth)) if depth > 0 => &line[1..],
+        (MboxFormat::Mboxo, Some(1)) => &line[1..],
+        _ => line,
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> MboxReader<R> {
+    pub fn new(reader: R, format: MboxFormat) -> Self {
+        Self {
+            reader,
+            format,
+            next: None,
+            started: false,
+            pos: 0,
+        }
+    }
+
+    /// Reads the next message, `None` at the end of the file.
+    pub async fn next_message(&mut self) -> io::Result<Option<MboxMessage>> {
+        let mut data = Vec::new();
+        Ok(self.scan(&mut data, false).await?.map(|entry| MboxMessage {
+            sender: entry.sender,
+            date: entry.date,
+            data,
+        }))
+    }
+
+    /// Finds the next message, keeping only its headers in memory.
+    pub async fn next_entry(&mut self) -> io::Result<Option<MboxEntry>> {
+        let mut headers = Vec::new();
+        let entry = self.scan(&mut headers, true).await?;
+        Ok(entry.map(|entry| MboxEntry { headers, ..entry }))
+    }
+
+    async fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<usize> {
+        line.clear();
+        let n = self.reader.read_until(b'\n', line).await?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Reads up to the next separator. Lines with quoting undone are
+    /// appended to `data`, only those of the header section if
+    /// `headers_only`.
+    async fn scan(
+        &mut self,
+        data: &mut Vec<u8>,
+        headers_only: bool,
+    ) -> io::Result<Option<MboxEntry>> {
+        let mut line = Vec::new();
+        if !self.started {
+            self.started = true;
+            // Anything before the first separator is not a message.
+            loop {
+                if self.read_line(&mut line).await? == 0 {
+                    return Ok(None);
+                }
+                if let Some(separator) = parse_separator(&line) {
+                    self.next = Some(separator);
+                    break;
+                }
+            }
+        }
+        let (sender, date) = match self.next.take() {
+            Some(separator) => separator,
+            None => return Ok(None),
+        };
+
+        let offset = self.pos;
+        let (mut len, mut size) = (0, 0);
+        let mut in_headers = true;
+        let mut blank = false;
+        // The blank line before a separator belongs to the mbox format.
+        let mut tail = 0;
+        let mut prev_crlf = false;
+        let mut first = true;
+        loop {
+            if self.read_line(&mut line).await? == 0 {
+                break;
+            }
+            if blank {
+                if let Some(separator) = parse_separator(&line) {
+                    self.next = Some(separator);
+                    break;
+                }
+            }
+            blank = line == b"\n" || line == b"\r\n";
+            tail = match (&line[..], prev_crlf) {
+                (b"\r\n", true) => 2,
+                (b"\n", _) if !first => 1,
+                _ => 0,
+            };
+            prev_crlf = line.ends_with(b"\r\n");
+            first = false;
+
+            let unquoted = unquote_line(self.format, &line);
+            if !headers_only || (in_headers && data.len() < MAX_HEADERS) {
+                data.extend_from_slice(unquoted);
+            }
+            in_headers &= !blank;
+            len += line.len() as u64;
+            size += unquoted.len() as u64;
+        }
+        if !headers_only {
+            data.truncate(data.len() - tail as usize);
+        }
+        Ok(Some(MboxEntry {
+            sender,
+            date,
+            offset,
+            len: len - tail,
+            size: size - tail,
+            headers: Vec::new(),
+        }))
+    }
+}
+
+/// Appends messages to an mbox file.
+pub struct MboxWriter {
+    file: File,
+    format: MboxFormat,
+}
+
+impl MboxWriter {
+    /// Opens `path` for appending, creating it if needed.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        format: MboxFormat,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self { file, format })
+    }
+
+    /// Appends a message with its separator. CRLF line endings are stored
+    /// as LF, as is usual for mbox files, and the message is written with a
+    /// single call so concurrent appenders do not interleave lines.
+    pub async fn append(&mut self, message: &MboxMessage) -> io::Result<()> {
+        let sender = message
+            .sender
+            .split_whitespace()
+            .next()
+            .unwrap_or("MAILER-DAEMON");
+        let mut out =
+            format!("From {} {}\n", sender, message.date.trim()).into_bytes();
+        for line in message.data.split_inclusive(|b| *b == b'\n') {
+            if matches!(
+                (self.format, from_depth(line)),
+                (MboxFormat::Mboxrd, Some(_)) | (MboxFormat::Mboxo, Some(0))
+            ) {
+                out.push(b'>');
+            }
+            let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+        out.push(b'\n');
+        self.file.write_all(&out).await?;
+        self.file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MESSAGES: [&str; 3] = [
+        "Subject: one\n\nFrom the start\n>From quoted\n\n",
+        "Subject: two\n\nbody\n",
+        "Subject: three\n\n>>From deep\nFrom \n",
+    ];
+
+    async fn read_all(data: &[u8], format: MboxFormat) -> Vec<MboxMessage> {
+        let mut reader = MboxReader::new(data, format);
+        let mut messages = Vec::new();
+        while let Some(m) = reader.next_message().await.unwrap() {
+            messages.push(m);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn mboxrd_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inbox");
+        let mut writer =
+            MboxWriter::open(&path, MboxFormat::Mboxrd).await.unwrap();
+        let messages: Vec<MboxMessage> = MESSAGES
+            .iter()
+            .map(|m| MboxMessage::new("root@jckeep.top", m.as_bytes().to_vec()))
+            .collect();
+        for m in &messages {
+            writer.append(m).await.unwrap();
+        }
+        let stored = std::fs::read_to_string(&path).unwrap();
+        assert!(stored.contains("\n>From the start\n>>From quoted\n"));
+        assert!(stored.contains("\n>>>From deep\n>From \n"));
+
+        let mut reader = open_mbox(&path, MboxFormat::Mboxrd).await.unwrap();
+        for m in &messages {
+            assert_eq!(reader.next_message().await.unwrap().as_ref(), Some(m));
+        }
+        assert!(reader.next_message().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn mboxo_quoting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inbox");
+        let mut writer =
+            MboxWriter::open(&path, MboxFormat::Mboxo).await.unwrap();
+        let m = MboxMessage::new("a@example.com", MESSAGES[0].into());
+        writer.append(&m).await.unwrap();
+        let stored = std::fs::read(&path).unwrap();
+        let read = read_all(&stored, MboxFormat::Mboxo).await;
+        // mboxo cannot tell the original `>From` from a quoted `From`.
+        assert_eq!(
+            read[0].data,
+            b"Subject: one\n\nFrom the start\nFrom quoted\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn separators() {
+        let data = b"junk before\n\
+            From MAILER-DAEMON Mon Oct 19 08:30:00 2026\n\
+            Subject: a\r\n\r\nFrom here on, text.\r\n\r\n\
+            From root@jckeep.top  Mon Oct 19 08:31:00 2026\n\
+            Subject: b\n\nbody";
+        let read = read_all(data, MboxFormat::Mboxo).await;
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].sender, "MAILER-DAEMON");
+        assert_eq!(read[0].date, "Mon Oct 19 08:30:00 2026");
+        assert_eq!(read[0].data, b"Subject: a\r\n\r\nFrom here on, text.\r\n");
+        assert_eq!(read[1].sender, "root@jckeep.top");
+        assert_eq!(read[1].data, b"Subject: b\n\nbody");
+
+        assert!(read_all(b"", MboxFormat::Mboxrd).await.is_empty());
+        assert!(read_all(b"From \nFrom x\n", MboxFormat::Mboxrd)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn entries_match_messages() {
+        let data = b"From a@b Mon Oct 19 08:30:00 2026\n\
+            Subject: a\r\n\r\n>From here\r\n\r\n\
+            From a@b Mon Oct 19 08:31:00 2026\n\
+            Subject: b\n\nbody\n\n\n\
+            From a@b Mon Oct 19 08:32:00 2026\n\
+            Subject: c";
+        let messages = read_all(data, MboxFormat::Mboxrd).await;
+        let mut reader = MboxReader::new(&data[..], MboxFormat::Mboxrd);
+        for m in &messages {
+            let entry = reader.next_entry().await.unwrap().unwrap();
+            let (start, end) = (entry.offset, entry.offset + entry.len);
+            let stored = &data[start as usize..end as usize];
+            let unquoted: Vec<u8> = stored
+                .split_inclusive(|b| *b == b'\n')
+                .flat_map(|line| unquote_line(MboxFormat::Mboxrd, line))
+                .copied()
+                .collect();
+            assert_eq!(unquoted, m.data);
+            assert_eq!(entry.size, m.data.len() as u64);
+            assert!(m.data.starts_with(&entry.headers));
+        }
+        assert!(reader.next_entry().await.unwrap().is_none());
+        assert_eq!(messages[0].data, b"Subject: a\r\n\r\nFrom here\r\n");
+        assert_eq!(messages[1].data, b"Subject: b\n\nbody\n\n");
+    }
+
+    #[tokio::test]
+    async fn crlf_is_stored_as_lf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inbox");
+        let mut writer =
+            MboxWriter::open(&path, MboxFormat::Mboxrd).await.unwrap();
+        let m =
+            MboxMessage::new("a@b", b"Subject: x\r\n\r\nFrom y\r\n".to_vec());
+        writer.append(&m).await.unwrap();
+        let stored = std::fs::read_to_string(&path).unwrap();
+        assert!(stored.ends_with("Subject: x\n\n>From y\n\n"));
+    }
+}
@@ -0,0 +1,744 @@
+use chrono::{DateTime, FixedOffset};
+
+use crate::{
+    address::{format_address_list, parse_address_list, Address},
+    mime::{
+        bit7_encode, decode_charset, decode_encoded_words, is_msg_id,
+        Alternative, ContentTransferEncoding, ContentType, Headers, MediaType,
+    },
+};
+
+/// A received message, or one part of a multipart message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// Header fields in order, unfolded but not decoded.
+    pub headers: Vec<(String, String)>,
+    /// Body as transferred, before transfer decoding.
+    pub body: Vec<u8>,
+    /// Parts of a multipart body, empty otherwise.
+    pub parts: Vec<Message>,
+    raw: Vec<u8>,
+}
+
+/// How `Message::forward` includes the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forward {
+    /// Below a separator in the text body.
+    Inline,
+    /// As a message/rfc822 attachment.
+    Attached,
+}
+
+/// A reply or forward built from a received message, to be completed and
+/// passed to `SmtpClient::send` or `mime_encode`.
+///
+/// The text in `content` goes above the quote. Forwards leave `to` empty.
+#[derive(Debug, Clone)]
+pub struct Draft {
+    pub to: String,
+    pub subject: String,
+    pub content: String,
+    /// Original message to attach as message/rfc822.
+    pub original: Option<Vec<u8>>,
+    pub headers: Headers,
+}
+
+impl Message {
+    pub fn parse(raw: &[u8]) -> Result<Self, &'static str> {
+        if raw.is_empty() {
+            return Err("empty message");
+        }
+        let (head, body) = split_head(raw);
+        let mut message = Self {
+            headers: parse_headers(head)?,
+            body: body.to_vec(),
+            parts: Vec::new(),
+            raw: raw.to_vec(),
+        };
+        let content_type = message.content_type();
+        if let Some(boundary) = content_type.param("boundary") {
+            if content_type.is_multipart() {
+                message.parts = split_parts(body, boundary)
+                    .into_iter()
+                    .map(Message::parse)
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+        Ok(message)
+    }
+
+    /// The message as it was parsed.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// First value of header `name`, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn subject(&self) -> String {
+        decode_encoded_words(self.header("Subject").unwrap_or_default())
+    }
+
+    pub fn from(&self) -> Vec<Address> {
+        self.addresses("From")
+    }
+
+    pub fn to(&self) -> Vec<Address> {
+        self.addresses("To")
+    }
+
+    pub fn cc(&self) -> Vec<Address> {
+        self.addresses("Cc")
+    }
+
+    pub fn reply_to(&self) -> Vec<Address> {
+        self.addresses("Reply-To")
+    }
+
+    /// Addresses in header `name` with display names decoded. Empty if the
+    /// header is missing or malformed.
+    fn addresses(&self, name: &str) -> Vec<Address> {
+        let mut list = self
+            .header(name)
+            .and_then(|v| parse_address_list(v).ok())
+            .unwrap_or_default();
+        for address in &mut list {
+            match address {
+                Address::Mailbox(m) => {
+                    m.name = m.name.as_deref().map(decode_encoded_words)
+                }
+                Address::Group(name, members) => {
+                    *name = decode_encoded_words(name);
+                    for m in members {
+                        m.name = m.name.as_deref().map(decode_encoded_words);
+                    }
+                }
+            }
+        }
+        list
+    }
+
+    pub fn date(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc2822(self.header("Date")?).ok()
+    }
+
+    pub fn message_id(&self) -> Option<String> {
+        msg_ids(self.header("Message-ID")?).into_iter().next()
+    }
+
+    pub fn in_reply_to(&self) -> Vec<String> {
+        msg_ids(self.header("In-Reply-To").unwrap_or_default())
+    }
+
+    pub fn references(&self) -> Vec<String> {
+        msg_ids(self.header("References").unwrap_or_default())
+    }
+
+    /// Content-Type, text/plain if missing or invalid as RFC 2045 specifies.
+    pub fn content_type(&self) -> MediaType {
+        self.header("Content-Type")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| ContentType::TextPlain.into())
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        self.header("Content-Disposition")
+            .map(|v| v.trim_start().to_ascii_lowercase())
+            .is_some_and(|v| v.starts_with("attachment"))
+    }
+
+    /// Body with the Content-Transfer-Encoding undone.
+    pub fn decoded_body(&self) -> Vec<u8> {
+        let cte = self
+            .header("Content-Transfer-Encoding")
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match cte.as_str() {
+            "base64" => {
+                let data: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                base64::decode(data).unwrap_or_default()
+            }
+            "quoted-printable" => quoted_printable::decode(
+                &self.body,
+                quoted_printable::ParseMode::Robust,
+            )
+            .unwrap_or_default(),
+            _ => self.body.clone(),
+        }
+    }
+
+    /// The readable text: this part if it is text/plain, otherwise the first
+    /// text/plain part that is not an attachment.
+    pub fn text(&self) -> Option<String> {
+        let content_type = self.content_type();
+        if content_type.is_multipart() {
+            return self.parts.iter().find_map(|p| p.text());
+        }
+        if content_type.essence() != "text/plain" || self.is_attachment() {
+            return None;
+        }
+        let charset = content_type.param("charset").unwrap_or("utf-8");
+        Some(decode_charset(&self.decoded_body(), charset))
+    }
+
+    /// Message-IDs of the thread up to and including this message.
+    fn thread_ids(&self) -> Vec<String> {
+        let mut ids = self.references();
+        if ids.is_empty() {
+            // RFC 5322 3.6.4: fall back to a single In-Reply-To.
+            let parent = self.in_reply_to();
+            if parent.len() == 1 {
+                ids = parent;
+            }
+        }
+        ids.extend(self.message_id());
+        ids
+    }
+
+    /// A reply to the sender, or with `all` to every recipient as well.
+    /// Callers replying to all should drop their own address from the Cc.
+    pub fn reply(&self, all: bool) -> Draft {
+        let mut to = self.reply_to();
+        if to.is_empty() {
+            to = self.from();
+        }
+        let mut cc: Vec<Address> = Vec::new();
+        if all {
+            let mut seen: Vec<String> = to
+                .iter()
+                .flat_map(|a| a.mailboxes())
+                .map(|m| m.addr_spec().to_ascii_lowercase())
+                .collect();
+            for address in self.to().into_iter().chain(self.cc()) {
+                for m in address.mailboxes() {
+                    let key = m.addr_spec().to_ascii_lowercase();
+                    if !seen.contains(&key) {
+                        seen.push(key);
+                        cc.push(Address::Mailbox(m.clone()));
+                    }
+                }
+            }
+        }
+
+        let mut content = String::from("\r\n");
+        let author = decode_encoded_words(self.header("From").unwrap_or(""));
+        match self.header("Date") {
+            Some(date) => {
+                content.push_str(&format!("On {}, {} wrote:\r\n", date, author))
+            }
+            None => content.push_str(&format!("{} wrote:\r\n", author)),
+        }
+        for line in self.text().unwrap_or_default().lines() {
+            if line.is_empty() || line.starts_with('>') {
+                content.push('>');
+            } else {
+                content.push_str("> ");
+            }
+            content.push_str(line);
+            content.push_str("\r\n");
+        }
+
+        Draft {
+            to: format_address_list(&to),
+            subject: prefixed("Re:", &self.subject()),
+            content,
+            original: None,
+            headers: Headers {
+                cc: Some(format_address_list(&cc)).filter(|_| !cc.is_empty()),
+                in_reply_to: self.message_id(),
+                references: self.thread_ids(),
+                ..Headers::default()
+            },
+        }
+    }
+
+    /// A forward, with `to` left for the caller. References keeps the
+    /// forward in the original thread for those who already have it.
+    pub fn forward(&self, mode: Forward) -> Draft {
+        let (content, original) = match mode {
+            Forward::Inline => {
+                let mut content = String::from(
+                    "\r\n---------- Forwarded message ----------\r\n",
+                );
+                for name in ["From", "Date", "Subject", "To", "Cc"] {
+                    if let Some(value) = self.header(name) {
+                        content.push_str(&format!(
+                            "{}: {}\r\n",
+                            name,
+                            decode_encoded_words(value)
+                        ));
+                    }
+                }
+                content.push_str("\r\n");
+                content.push_str(&self.text().unwrap_or_default());
+                (content, None)
+            }
+            Forward::Attached => (String::new(), Some(self.raw.clone())),
+        };
+        Draft {
+            to: String::new(),
+            subject: prefixed("Fwd:", &self.subject()),
+            content,
+            original,
+            headers: Headers {
+                references: self.thread_ids(),
+                ..Headers::default()
+            },
+        }
+    }
+}
+
+impl Draft {
+    /// text/plain, or multipart/mixed when the original is attached.
+    pub fn content_type(&self) -> MediaType {
+        match self.original {
+            Some(_) => ContentType::MultipartMixed.into(),
+            None => ContentType::TextPlain.into(),
+        }
+    }
+
+    /// Parts for a multipart draft: the text, then the original message.
+    pub fn attach(&self) -> Option<Vec<Alternative>> {
+        let original = self.original.as_ref()?;
+        let text = Alternative {
+            filename: None,
+            content: self.content.clone(),
+            source: None,
+            charset: None,
+            content_type: ContentType::TextPlain.into(),
+            encoding: ContentTransferEncoding::QuotedPrintable,
+            disposition: None,
+            content_id: None,
+        };
+        // RFC 2046 5.2.1 allows only 7bit, 8bit or binary for message/rfc822,
+        // so originals that are not 7bit clean go out as 8bit.
+        let encoding = match bit7_encode(original) {
+            Ok(_) => ContentTransferEncoding::Bit7,
+            Err(_) => ContentTransferEncoding::Bit8,
+        };
+        let message = Alternative {
+            content_type: MediaType::new("message", "rfc822"),
+            encoding,
+            ..Alternative::from_bytes("forwarded.eml", original.clone())
+        };
+        Some(vec![text, message])
+    }
+}
+
+/// Reply and forward prefixes, including those of Chinese mail clients.
+const PREFIXES: &[&str] = &["re", "fw", "fwd", "回复", "转发"];
+
+/// Strips leading `Re:`, `Fwd:` and similar prefixes, as in `Re[2]:` or
+/// `Re: Fwd: Re:`, leaving the subject a thread was started with.
+pub fn normalize_subject(subject: &str) -> &str {
+    let mut rest = subject.trim();
+    'strip: loop {
+        for prefix in PREFIXES {
+            let head = match rest.get(..prefix.len()) {
+                Some(head) if head.eq_ignore_ascii_case(prefix) => head,
+                _ => continue,
+            };
+            let mut tail = &rest[head.len()..];
+            // Counters such as `Re[2]:` or `Re(2):`.
+            if let Some(t) = tail.strip_prefix(['[', '(']) {
+                if let Some(end) = t.find([']', ')']) {
+                    if t[..end].bytes().all(|b| b.is_ascii_digit()) {
+                        tail = &t[end + 1..];
+                    }
+                }
+            }
+            if let Some(t) = tail.strip_prefix([':', '：']) {
+                rest = t.trim_start();
+                continue 'strip;
+            }
+        }
+        return rest;
+    }
+}
+
+/// Adds `prefix` unless the subject already starts with it.
+fn prefixed(prefix: &str, subject: &str) -> String {
+    let head = prefix.trim_end_matches(':');
+    let has = |p: &str| {
+        subject
+            .get(..p.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(p))
+            && subject[p.len()..].starts_with(':')
+    };
+    let already = match head {
+        "Fwd" => has("Fwd") || has("Fw"),
+        _ => has(head),
+    };
+    if already {
+        subject.to_string()
+    } else {
+        format!("{} {}", prefix, subject)
+    }
+}
+
+/// Message-IDs in a header value, in order.
+fn msg_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let id = &rest[start..end];
+        if is_msg_id(id) {
+            ids.push(id.to_string());
+        }
+        rest = &rest[end..];
+    }
+    ids
+}
+
+/// Byte offset just past the line starting at `pos`.
+fn line_end(data: &[u8], pos: usize) -> usize {
+    data[pos..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(data.len(), |i| pos + i + 1)
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Splits at the first empty line into header and body.
+fn split_head(raw: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    while pos < raw.len() {
+        let end = line_end(raw, pos);
+        if trim_newline(&raw[pos..end]).is_empty() {
+            return (&raw[..pos], &raw[end..]);
+        }
+        pos = end;
+    }
+    (raw, &[])
+}
+
+fn parse_headers(head: &[u8]) -> Result<Vec<(String, String)>, &'static str> {
+    let head = String::from_utf8_lossy(head);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for (i, line) in head.lines().enumerate() {
+        // The envelope line of a message taken from an mbox file.
+        if i == 0 && line.starts_with("From ") {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            match headers.last_mut() {
+                Some((_, value)) => value.push_str(line),
+                None => return Err("header continuation without a field"),
+            }
+            continue;
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(' ') => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            _ => return Err("malformed header line"),
+        }
+    }
+    Ok(headers)
+}
+
+/// Bodies of the parts between `--boundary` delimiter lines. The line break
+/// before each delimiter belongs to the delimiter.
+fn split_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    while pos < body.len() {
+        let end = line_end(body, pos);
+        let line = trim_newline(&body[pos..end]);
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            let rest = rest.trim_ascii_end();
+            if rest.is_empty() || rest == b"--" {
+                if let Some(start) = start {
+                    parts.push(trim_newline(&body[start..pos]));
+                }
+                if rest == b"--" {
+                    return parts;
+                }
+                start = Some(end);
+            }
+        }
+        pos = end;
+    }
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mime::mime_encode;
+
+    const INCIDENT: &str = "From: Alert Bot <alerts@jckeep.top>\r\n\
+        To: oncall@jckeep.top, Ops <ops@jckeep.top>\r\n\
+        Cc: ALERTS@jckeep.top, dba@jckeep.top\r\n\
+        Date: Mon, 19 Oct 2026 08:30:00 +0800\r\n\
+        Subject: =?utf-8?B?56OB55uY5ZGK6K2m?= disk full\r\n\
+        Message-ID: <incident.2@jckeep.top>\r\n\
+        In-Reply-To: <incident.1@jckeep.top>\r\n\
+        References: <incident.0@jckeep.top>\r\n \
+        <incident.1@jckeep.top>\r\n\
+        \r\n\
+        /var is at 99%.\r\n\
+        \r\n\
+        > previous alert\r\n";
+
+    #[test]
+    fn parse_headers_and_text() {
+        let m = Message::parse(INCIDENT.as_bytes()).unwrap();
+        assert_eq!(m.subject(), "磁盘告警 disk full");
+        assert_eq!(m.message_id().as_deref(), Some("<incident.2@jckeep.top>"));
+        assert_eq!(m.references().len(), 2);
+        assert_eq!(m.to().len(), 2);
+        assert_eq!(m.date().unwrap().timestamp(), 1792369800);
+        assert_eq!(
+            m.text().unwrap(),
+            "/var is at 99%.\r\n\r\n> previous alert\r\n"
+        );
+        assert!(Message::parse(b"no colon here\r\n\r\nbody").is_err());
+    }
+
+    #[tokio::test]
+    async fn parse_encoded_multipart() {
+        let attach = [
+            Alternative {
+                filename: None,
+                content: String::from("café = ok"),
+                source: None,
+                charset: None,
+                content_type: ContentType::TextPlain.into(),
+                encoding: ContentTransferEncoding::QuotedPrintable,
+                disposition: None,
+                content_id: None,
+            },
+            Alternative::from_bytes("log.txt", b"attached text".to_vec()),
+        ];
+        let raw = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "multi",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&attach),
+            None,
+        )
+        .await
+        .unwrap();
+        let m = Message::parse(&raw).unwrap();
+        assert_eq!(m.parts.len(), 2);
+        assert_eq!(m.text().unwrap(), "café = ok");
+        assert!(m.parts[1].is_attachment());
+        assert_eq!(m.parts[1].decoded_body(), b"attached text");
+    }
+
+    #[test]
+    fn reply_threads_and_quotes() {
+        let m = Message::parse(INCIDENT.as_bytes()).unwrap();
+        let reply = m.reply(false);
+        assert_eq!(reply.to, "Alert Bot <alerts@jckeep.top>");
+        assert_eq!(reply.subject, "Re: 磁盘告警 disk full");
+        assert_eq!(
+            reply.headers.in_reply_to.as_deref(),
+            Some("<incident.2@jckeep.top>")
+        );
+        assert_eq!(
+            reply.headers.references,
+            [
+                "<incident.0@jckeep.top>",
+                "<incident.1@jckeep.top>",
+                "<incident.2@jckeep.top>"
+            ]
+        );
+        assert!(reply.headers.cc.is_none());
+        assert!(reply.content.contains(
+            "On Mon, 19 Oct 2026 08:30:00 +0800, Alert Bot \
+             <alerts@jckeep.top> wrote:\r\n\
+             > /var is at 99%.\r\n>\r\n>> previous alert\r\n"
+        ));
+
+        let again = Message::parse(
+            format!("Subject: RE: x\r\nFrom: {}\r\n\r\n", "a@example.com")
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(again.reply(false).subject, "RE: x");
+        assert!(again.reply(false).headers.references.is_empty());
+    }
+
+    #[test]
+    fn reply_all() {
+        let m = Message::parse(INCIDENT.as_bytes()).unwrap();
+        let mut reply = m.reply(true);
+        assert_eq!(
+            reply.headers.cc.as_deref(),
+            Some("oncall@jckeep.top, Ops <ops@jckeep.top>, dba@jckeep.top")
+        );
+        reply.headers.message_id = Some(String::from("<r@jckeep.top>"));
+        let (header, _) = crate::mime::message_header(
+            "oncall@jckeep.top",
+            &reply.to,
+            &reply.subject,
+            ContentTransferEncoding::Base64,
+            &reply.content_type(),
+            None,
+            &reply.headers,
+        )
+        .unwrap();
+        assert!(header.contains(
+            "Cc: oncall@jckeep.top, Ops <ops@jckeep.top>, dba@jckeep.top\r\n"
+        ));
+        assert!(header.contains(
+            "Message-ID: <r@jckeep.top>\r\n\
+             In-Reply-To: <incident.2@jckeep.top>\r\n\
+             References: <incident.0@jckeep.top>\r\n \
+             <incident.1@jckeep.top>\r\n <incident.2@jckeep.top>\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn forward_inline_and_attached() {
+        let m = Message::parse(INCIDENT.as_bytes()).unwrap();
+        let inline = m.forward(Forward::Inline);
+        assert_eq!(inline.subject, "Fwd: 磁盘告警 disk full");
+        assert!(inline.headers.in_reply_to.is_none());
+        assert_eq!(inline.headers.references.len(), 3);
+        assert!(inline.content.contains(
+            "---------- Forwarded message ----------\r\n\
+             From: Alert Bot <alerts@jckeep.top>\r\n"
+        ));
+        assert!(inline.content.ends_with("> previous alert\r\n"));
+        assert!(inline.attach().is_none());
+
+        let mut attached = m.forward(Forward::Attached);
+        attached.to = String::from("boss@jckeep.top");
+        attached.content = String::from("FYI");
+        let parts = attached.attach().unwrap();
+        let raw = mime_encode(
+            "oncall@jckeep.top",
+            &attached.to,
+            &attached.subject,
+            ContentTransferEncoding::Base64,
+            attached.content_type(),
+            &attached.content,
+            Some(&parts),
+            Some(&attached.headers),
+        )
+        .await
+        .unwrap();
+        let sent = Message::parse(&raw).unwrap();
+        assert_eq!(sent.references(), attached.headers.references);
+        assert!(sent.header("In-Reply-To").is_none());
+        assert_eq!(sent.text().unwrap(), "FYI");
+        let original = &sent.parts[1];
+        assert_eq!(original.content_type().essence(), "message/rfc822");
+        assert_eq!(original.header("Content-Transfer-Encoding"), Some("7bit"));
+        let inner = Message::parse(&original.decoded_body()).unwrap();
+        assert_eq!(inner.message_id(), m.message_id());
+    }
+
+    #[tokio::test]
+    async fn forward_multipart_attached() {
+        let parts = [
+            Alternative::from_bytes("notes.txt", b"first\r\n".to_vec()),
+            Alternative::from_bytes("data.bin", vec![0xff; 100]),
+        ];
+        let raw = mime_encode(
+            "alerts@jckeep.top",
+            "oncall@jckeep.top",
+            "report",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&parts),
+            None,
+        )
+        .await
+        .unwrap();
+        let m = Message::parse(&raw).unwrap();
+        assert_eq!(m.parts.len(), 2);
+
+        // The original's own boundary must not end the outer parts.
+        let mut attached = m.forward(Forward::Attached);
+        attached.to = String::from("boss@jckeep.top");
+        attached.content = String::from("FYI");
+        let raw = mime_encode(
+            "oncall@jckeep.top",
+            &attached.to,
+            &attached.subject,
+            ContentTransferEncoding::Base64,
+            attached.content_type(),
+            &attached.content,
+            Some(&attached.attach().unwrap()),
+            Some(&attached.headers),
+        )
+        .await
+        .unwrap();
+        let sent = Message::parse(&raw).unwrap();
+        assert_eq!(sent.parts.len(), 2);
+        assert_eq!(sent.text().unwrap(), "FYI");
+        let inner = Message::parse(&sent.parts[1].decoded_body()).unwrap();
+        assert_eq!(inner.message_id(), m.message_id());
+        assert_eq!(inner.parts.len(), 2);
+        assert_eq!(inner.parts[1].decoded_body(), vec![0xff; 100]);
+    }
+
+    #[tokio::test]
+    async fn forward_8bit_attached() {
+        let m = Message::parse(
+            "From: a@jckeep.top\r\nSubject: x\r\n\r\n磁盘告警\r\n".as_bytes(),
+        )
+        .unwrap();
+        let mut attached = m.forward(Forward::Attached);
+        attached.to = String::from("boss@jckeep.top");
+        let raw = mime_encode(
+            "oncall@jckeep.top",
+            &attached.to,
+            &attached.subject,
+            ContentTransferEncoding::Base64,
+            attached.content_type(),
+            &attached.content,
+            Some(&attached.attach().unwrap()),
+            Some(&attached.headers),
+        )
+        .await
+        .unwrap();
+        let sent = Message::parse(&raw).unwrap();
+        let original = &sent.parts[1];
+        assert_eq!(original.header("Content-Transfer-Encoding"), Some("8bit"));
+        let inner = Message::parse(&original.decoded_body()).unwrap();
+        assert_eq!(inner.text().unwrap(), "磁盘告警\r\n");
+    }
+
+    #[test]
+    fn subject_prefixes() {
+        assert_eq!(prefixed("Fwd:", "FW: x"), "FW: x");
+        assert_eq!(prefixed("Re:", "Fwd: x"), "Re: Fwd: x");
+        assert_eq!(prefixed("Re:", "Reboot"), "Re: Reboot");
+        assert_eq!(normalize_subject("Re: Fwd: RE[2]: disk"), "disk");
+        assert_eq!(normalize_subject("回复：转发: 告警"), "告警");
+        assert_eq!(normalize_subject("Reboot"), "Reboot");
+    }
+}
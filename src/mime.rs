@@ -1,15 +1,48 @@
 #![allow(unused)]
 
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use base64::encode;
-use bytes::BufMut;
-use encoding::{EncoderTrap, Encoding};
-use tokio::{fs::File, io::AsyncReadExt};
+use bytes::{BufMut, Bytes};
+use chrono::{DateTime, Local, Utc};
+use encoding::{
+    all, label::encoding_from_whatwg_label, DecoderTrap, EncoderTrap,
+    EncodingRef,
+};
+use futures_util::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use md5::{Digest, Md5};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::address::{format_address_list, parse_address_list, Address};
 
 #[derive(Debug, Clone, Copy)]
 pub enum ContentTransferEncoding {
     Base64,
     Bit7,
     QuotedPrintable,
+    /// Needs a server that supports 8BITMIME (RFC 6152).
+    Bit8,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +51,7 @@ pub enum ContentType {
     TextPlain,
     MultipartMixed,
     MultipartAlternative,
+    MultipartRelated,
     ImageJpeg,
     ImageGif,
     ImagePng,
@@ -37,22 +71,452 @@ pub enum ContentDisposition {
     Inline,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CharSet {
     Utf8,
+    UsAscii,
+    Gbk,
+    Gb2312,
+    Gb18030,
+    Big5,
+    ShiftJis,
+    EucJp,
+    Iso2022Jp,
+    EucKr,
+    Iso8859_1,
+    Iso8859_2,
+    Iso8859_5,
+    Iso8859_7,
+    Iso8859_15,
+    Koi8R,
+    Windows1251,
+    Windows1252,
+}
+
+/// Maximum length of a line in a message body, excluding the CRLF
+/// (RFC 5322 2.1.1).
+pub const MAX_LINE_LENGTH: usize = 998;
+
+/// Maximum length of a base64 or quoted-printable encoded line, excluding
+/// the CRLF (RFC 2045 6.7, 6.8).
+pub const ENCODED_LINE_LENGTH: usize = 76;
+
+impl CharSet {
+    pub const VALUE_MAP: [&'static str; 18] = [
+        "utf-8",
+        "us-ascii",
+        "gbk",
+        "gb2312",
+        "gb18030",
+        "big5",
+        "shift_jis",
+        "euc-jp",
+        "iso-2022-jp",
+        "euc-kr",
+        "iso-8859-1",
+        "iso-8859-2",
+        "iso-8859-5",
+        "iso-8859-7",
+        "iso-8859-15",
+        "koi8-r",
+        "windows-1251",
+        "windows-1252",
+    ];
+
+    const ALL: [CharSet; 18] = [
+        CharSet::Utf8,
+        CharSet::UsAscii,
+        CharSet::Gbk,
+        CharSet::Gb2312,
+        CharSet::Gb18030,
+        CharSet::Big5,
+        CharSet::ShiftJis,
+        CharSet::EucJp,
+        CharSet::Iso2022Jp,
+        CharSet::EucKr,
+        CharSet::Iso8859_1,
+        CharSet::Iso8859_2,
+        CharSet::Iso8859_5,
+        CharSet::Iso8859_7,
+        CharSet::Iso8859_15,
+        CharSet::Koi8R,
+        CharSet::Windows1251,
+        CharSet::Windows1252,
+    ];
+
+    /// Looks up a charset by its MIME name or one of its aliases, such as
+    /// `cp936` for GBK or `sjis` for Shift_JIS.
+    pub fn from_label(label: &str) -> Option<CharSet> {
+        let label = label.trim();
+        if let Some(i) = CharSet::VALUE_MAP
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(label))
+        {
+            return Some(CharSet::ALL[i]);
+        }
+        const ALIASES: &[(&str, CharSet)] = &[
+            ("ascii", CharSet::UsAscii),
+            ("cp936", CharSet::Gbk),
+            ("ms936", CharSet::Gbk),
+            ("cp932", CharSet::ShiftJis),
+            ("ms932", CharSet::ShiftJis),
+            ("cp949", CharSet::EucKr),
+            ("latin1", CharSet::Iso8859_1),
+        ];
+        if let Some((_, charset)) = ALIASES
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(label))
+        {
+            return Some(*charset);
+        }
+        let name = encoding_from_whatwg_label(label)?.name();
+        CharSet::ALL
+            .iter()
+            .find(|charset| charset.encoding().name() == name)
+            .copied()
+    }
+
+    pub fn encoding(&self) -> EncodingRef {
+        match self {
+            CharSet::Utf8 => all::UTF_8,
+            CharSet::UsAscii => all::ASCII,
+            // GBK is a superset of GB2312, as decoders in the wild assume.
+            CharSet::Gbk | CharSet::Gb2312 => all::GBK,
+            CharSet::Gb18030 => all::GB18030,
+            CharSet::Big5 => all::BIG5_2003,
+            CharSet::ShiftJis => all::WINDOWS_31J,
+            CharSet::EucJp => all::EUC_JP,
+            CharSet::Iso2022Jp => all::ISO_2022_JP,
+            CharSet::EucKr => all::WINDOWS_949,
+            CharSet::Iso8859_1 => all::ISO_8859_1,
+            CharSet::Iso8859_2 => all::ISO_8859_2,
+            CharSet::Iso8859_5 => all::ISO_8859_5,
+            CharSet::Iso8859_7 => all::ISO_8859_7,
+            CharSet::Iso8859_15 => all::ISO_8859_15,
+            CharSet::Koi8R => all::KOI8_R,
+            CharSet::Windows1251 => all::WINDOWS_1251,
+            CharSet::Windows1252 => all::WINDOWS_1252,
+        }
+    }
+
+    /// Decodes `data` to UTF-8, replacing malformed sequences with U+FFFD.
+    pub fn decode(&self, data: &[u8]) -> String {
+        self.encoding()
+            .decode(data, DecoderTrap::Replace)
+            .unwrap_or_default()
+    }
+
+    /// Encodes `text` in this charset. Fails if `text` has characters the
+    /// charset cannot represent.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, &'static str> {
+        self.encoding()
+            .encode(text, EncoderTrap::Strict)
+            .map_err(|_| "text not representable in charset")
+    }
+}
+
+/// Decodes `data` in the charset named by `label` to UTF-8. Labels unknown
+/// to `CharSet` are looked up in the WHATWG table, and data in a charset
+/// that is unknown altogether is decoded as UTF-8.
+pub fn decode_charset(data: &[u8], label: &str) -> String {
+    let encoding = match CharSet::from_label(label) {
+        Some(charset) => charset.encoding(),
+        None => encoding_from_whatwg_label(label).unwrap_or(all::UTF_8),
+    };
+    encoding
+        .decode(data, DecoderTrap::Replace)
+        .unwrap_or_default()
+}
+
+/// Decodes RFC 2047 encoded words such as `=?GBK?B?suLK1A==?=` in a header
+/// value to UTF-8. Whitespace between adjacent encoded words is dropped, and
+/// malformed words are left as they are.
+pub fn decode_encoded_words(value: &str) -> String {
+    fn decode_word(word: &str) -> Option<String> {
+        let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+        let mut fields = inner.splitn(3, '?');
+        let charset = fields.next()?;
+        // RFC 2231 allows a language suffix, as in `utf-8*en`.
+        let charset = charset.split('*').next()?;
+        let kind = fields.next()?;
+        let text = fields.next()?;
+        let data = match kind {
+            "B" | "b" => base64::decode(text).ok()?,
+            "Q" | "q" => {
+                let mut data = Vec::new();
+                let mut bytes = text.bytes();
+                while let Some(b) = bytes.next() {
+                    match b {
+                        b'_' => data.push(b' '),
+                        b'=' => {
+                            let hex = [bytes.next()?, bytes.next()?];
+                            let hex = std::str::from_utf8(&hex).ok()?;
+                            data.push(u8::from_str_radix(hex, 16).ok()?);
+                        }
+                        _ => data.push(b),
+                    }
+                }
+                data
+            }
+            _ => return None,
+        };
+        Some(decode_charset(&data, charset))
+    }
+
+    /// Length of the encoded word `s` starts with, up to and including `?=`.
+    fn word_len(s: &str) -> Option<usize> {
+        let q1 = 2 + s[2..].find('?')?;
+        let q2 = q1 + 1 + s[q1 + 1..].find('?')?;
+        Some(q2 + 1 + s[q2 + 1..].find("?=")? + 2)
+    }
+
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = &rest[start..];
+        let decoded = word_len(word)
+            .and_then(|len| Some((start + len, decode_word(&word[..len])?)));
+        match decoded {
+            Some((end, text)) => {
+                let between = &rest[..start];
+                if !(after_word && between.trim().is_empty()) {
+                    out.push_str(between);
+                }
+                out.push_str(&text);
+                after_word = true;
+                rest = &rest[end..];
+            }
+            None => {
+                out.push_str(&rest[..start + 2]);
+                after_word = false;
+                rest = &rest[start + 2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Encodes a header value as RFC 2047 UTF-8 `B` words if it is not plain
+/// ASCII. Words stay within 75 characters and are folded onto new lines.
+pub fn encode_words(value: &str) -> String {
+    if value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+        return value.to_string();
+    }
+    // `=?utf-8?B?` and `?=` leave 63 characters, 45 bytes of base64.
+    const WORD_BYTES: usize = 45;
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for (i, c) in value.char_indices() {
+        if i + c.len_utf8() - start > WORD_BYTES {
+            words.push(format!("=?utf-8?B?{}?=", encode(&value[start..end])));
+            start = end;
+        }
+        end = i + c.len_utf8();
+    }
+    words.push(format!("=?utf-8?B?{}?=", encode(&value[start..end])));
+    words.join("\r\n ")
+}
+
+/// Replaces CR, LF, NUL and other control characters, which would end or
+/// corrupt a header line, with spaces. Tabs are kept.
+pub fn sanitize_header(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() && c != '\t' { ' ' } else { c })
+        .collect()
+}
+
+/// An unstructured value such as `Subject`, sanitised and then encoded.
+pub fn encode_header(value: &str) -> String {
+    encode_words(&sanitize_header(value))
+}
+
+/// A parameter value as a quoted string, so quotes and semicolons in it
+/// cannot close the value and start another parameter.
+fn quote_param(value: &str) -> String {
+    let value = sanitize_header(value);
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Drops characters that are not allowed in an RFC 2045 token.
+fn header_token(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(*c))
+        .collect()
+}
+
+impl ContentDisposition {
+    pub const VALUE_MAP: [&'static str; 2] = ["attachment", "inline"];
 }
 
 impl ContentTransferEncoding {
-    pub const VALUE_MAP: [&'static str; 3] =
-        ["base64", "7bit", "quoted-printable"];
+    pub const VALUE_MAP: [&'static str; 4] =
+        ["base64", "7bit", "quoted-printable", "8bit"];
+
+    /// Encodes `data` for use as a part body with this transfer encoding.
+    ///
+    /// Fails for `Bit7` and `Bit8` when `data` is not 7bit or 8bit clean.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        match self {
+            ContentTransferEncoding::Base64 => Ok(base64_encode(data)),
+            ContentTransferEncoding::Bit7 => bit7_encode(data),
+            ContentTransferEncoding::QuotedPrintable => Ok(qp_encode(data)),
+            ContentTransferEncoding::Bit8 => bit8_encode(data),
+        }
+    }
+}
+
+/// Base64 encodes `data`, wrapping the output at 76 columns with CRLF.
+pub fn base64_encode(data: &[u8]) -> Vec<u8> {
+    let encoded = encode(data);
+    let mut out = Vec::with_capacity(
+        encoded.len() + encoded.len() / ENCODED_LINE_LENGTH * 2,
+    );
+    for (i, line) in encoded.as_bytes().chunks(ENCODED_LINE_LENGTH).enumerate()
+    {
+        if i > 0 {
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(line);
+    }
+    out
+}
+
+/// Quoted-printable encodes `data` as text.
+///
+/// Line breaks (CRLF or bare LF) become CRLF hard breaks, whitespace before
+/// a hard break is encoded, and lines longer than 76 columns are split with
+/// soft line breaks. A leading `.` is left to SMTP dot-stuffing, as in
+/// `bit7_encode`.
+pub fn qp_encode(data: &[u8]) -> Vec<u8> {
+    qp_encode_prefix(data, data.len(), &mut 0)
+}
+
+/// Quoted-printable encodes `data[..end]`, starting at output column `col`.
+/// Bytes past `end` are only looked at to tell whether whitespace ends a
+/// line, so a line can be encoded across several calls.
+fn qp_encode_prefix(data: &[u8], end: usize, col: &mut usize) -> Vec<u8> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut out = Vec::with_capacity(end * 3 / 2);
+    let mut i = 0;
+    while i < end {
+        let b = data[i];
+        if b == b'\n' || (b == b'\r' && data.get(i + 1) == Some(&b'\n')) {
+            out.extend_from_slice(b"\r\n");
+            *col = 0;
+            i += if b == b'\r' { 2 } else { 1 };
+            continue;
+        }
+
+        let at_eol = match data.get(i + 1) {
+            None | Some(b'\n') => true,
+            Some(b'\r') => data.get(i + 2) == Some(&b'\n'),
+            _ => false,
+        };
+        let literal = match b {
+            b'=' => false,
+            b' ' | b'\t' => !at_eol,
+            b'!'..=b'~' => true,
+            _ => false,
+        };
+        let width = if literal { 1 } else { 3 };
+
+        // Keep room for the trailing `=` of a soft break unless this is the
+        // last character on the line.
+        let limit = if at_eol {
+            ENCODED_LINE_LENGTH
+        } else {
+            ENCODED_LINE_LENGTH - 1
+        };
+        if *col + width > limit {
+            out.extend_from_slice(b"=\r\n");
+            *col = 0;
+        }
+
+        if literal {
+            out.push(b);
+        } else {
+            out.extend_from_slice(&[
+                b'=',
+                HEX[(b >> 4) as usize],
+                HEX[(b & 0xf) as usize],
+            ]);
+        }
+        *col += width;
+        i += 1;
+    }
+    out
+}
+
+/// Validates `data` as 7bit text and normalises its line endings to CRLF.
+///
+/// Fails on 8bit bytes, NUL, bare CR, or lines longer than 998 octets.
+pub fn bit7_encode(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    line_encode(data, false).map_err(|e| match e {
+        LineError::Octet => "invalid octet in 7bit data",
+        LineError::TooLong => "line too long for 7bit data",
+    })
+}
+
+/// Validates `data` as 8bit text (RFC 2045 section 2.8) and normalises its
+/// line endings to CRLF.
+///
+/// Fails on NUL, bare CR, or lines longer than 998 octets.
+pub fn bit8_encode(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    line_encode(data, true).map_err(|e| match e {
+        LineError::Octet => "invalid octet in 8bit data",
+        LineError::TooLong => "line too long for 8bit data",
+    })
+}
+
+enum LineError {
+    Octet,
+    TooLong,
+}
+
+/// Checks and normalises lines for `bit7_encode` and `bit8_encode`.
+fn line_encode(data: &[u8], eight_bit: bool) -> Result<Vec<u8>, LineError> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 32);
+    let mut col = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        match b {
+            b'\n' => {
+                out.extend_from_slice(b"\r\n");
+                col = 0;
+            }
+            b'\r' if data.get(i + 1) == Some(&b'\n') => {
+                out.extend_from_slice(b"\r\n");
+                col = 0;
+                i += 1;
+            }
+            0 | b'\r' => return Err(LineError::Octet),
+            0x80..=0xff if !eight_bit => return Err(LineError::Octet),
+            _ => {
+                col += 1;
+                if col > MAX_LINE_LENGTH {
+                    return Err(LineError::TooLong);
+                }
+                out.push(b);
+            }
+        }
+        i += 1;
+    }
+    Ok(out)
 }
 
 impl ContentType {
-    pub const VALUE_MAP: [&'static str; 15] = [
+    pub const VALUE_MAP: [&'static str; 16] = [
         "text/html",
         "text/plain",
         "multipart/mixed",
         "multipart/alternative",
+        "multipart/related",
         "image/jpeg",
         "image/gif",
         "image/png",
@@ -67,190 +531,1883 @@ impl ContentType {
     ];
 }
 
-pub fn parse_content_type(s: &str) -> ContentType {
-    if s.ends_with(".jpeg") || s.ends_with(".jpg") {
-        ContentType::ImageJpeg
-    } else if s.ends_with(".mp4") || s.ends_with(".m4a") {
-        ContentType::VideoMp4
-    } else if s.ends_with(".gif") {
-        ContentType::ImageGif
-    } else if s.ends_with(".png") {
-        ContentType::ImagePng
-    } else if s.ends_with(".pdf") {
-        ContentType::ApplicationPdf
-    } else if s.ends_with(".rar") {
-        ContentType::ApplicationRar
-    } else if s.ends_with(".zip") {
-        ContentType::ApplicationZip
-    } else if s.ends_with(".docx") {
-        ContentType::ApplicationWORD
-    } else if s.ends_with(".pptx") {
-        ContentType::ApplicationPPTX
-    } else if s.ends_with(".xls") {
-        ContentType::ApplicationEXCEL
-    } else if s.ends_with(".c")
-        || s.ends_with(".rs")
-        || s.ends_with(".cpp")
-        || s.ends_with(".h")
-        || s.ends_with(".txt")
-        || s.ends_with(".toml")
+/// A MIME media type such as `text/plain; charset="utf-8"` (RFC 2045 5.1).
+///
+/// Type, subtype and parameter names are case-insensitive and kept in
+/// lower case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    type_: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub fn new(type_: &str, subtype: &str) -> Self {
+        Self {
+            type_: type_.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn type_(&self) -> &str {
+        &self.type_
+    }
+
+    pub fn subtype(&self) -> &str {
+        &self.subtype
+    }
+
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Sets parameter `name`, replacing any previous value.
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        match self.params.iter_mut().find(|(n, _)| *n == name) {
+            Some(param) => param.1 = String::from(value),
+            None => self.params.push((name, String::from(value))),
+        }
+        self
+    }
+
+    /// `type/subtype` without parameters.
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.type_, self.subtype)
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.type_ == "multipart"
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.type_ == "text"
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (type_, subtype) =
+            (header_token(&self.type_), header_token(&self.subtype));
+        write!(f, "{}/{}", type_, subtype)?;
+        for (name, value) in &self.params {
+            write!(f, "; {}={}", header_token(name), quote_param(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn is_token(s: &str) -> bool {
+            !s.is_empty()
+                && s.bytes().all(|b| {
+                    b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?=".contains(&b)
+                })
+        }
+
+        let (essence, mut rest) = match s.find(';') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, ""),
+        };
+        let (type_, subtype) = match essence.trim().split_once('/') {
+            Some((t, st)) if is_token(t) && is_token(st) => (t, st),
+            _ => return Err("invalid media type"),
+        };
+        let mut media_type = MediaType::new(type_, subtype);
+
+        loop {
+            rest = rest.trim_start_matches([' ', '\t', ';']);
+            if rest.is_empty() {
+                break;
+            }
+            let (name, value) = match rest.split_once('=') {
+                Some((name, value)) if is_token(name.trim()) => {
+                    (name.trim(), value.trim_start())
+                }
+                _ => return Err("invalid media type parameter"),
+            };
+            let mut parsed = String::new();
+            if let Some(quoted) = value.strip_prefix('"') {
+                let mut chars = quoted.char_indices();
+                rest = loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => parsed.push(c),
+                            None => return Err("unterminated quoted string"),
+                        },
+                        Some((i, '"')) => break &quoted[i + 1..],
+                        Some((_, c)) => parsed.push(c),
+                        None => return Err("unterminated quoted string"),
+                    }
+                };
+            } else {
+                let end = value.find(';').unwrap_or(value.len());
+                parsed.push_str(value[..end].trim_end());
+                rest = &value[end..];
+            }
+            media_type = media_type.with_param(name, &parsed);
+        }
+        Ok(media_type)
+    }
+}
+
+impl From<ContentType> for MediaType {
+    fn from(content_type: ContentType) -> Self {
+        ContentType::VALUE_MAP[content_type as usize]
+            .parse()
+            .unwrap()
+    }
+}
+
+/// Extensions known out of the box, matched case-insensitively.
+const BUILTIN_EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("c", "text/plain"),
+    ("h", "text/plain"),
+    ("cpp", "text/plain"),
+    ("hpp", "text/plain"),
+    ("rs", "text/plain"),
+    ("toml", "text/plain"),
+    ("py", "text/x-python"),
+    ("sh", "application/x-sh"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("eml", "message/rfc822"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("pdf", "application/pdf"),
+    ("rtf", "application/rtf"),
+    ("zip", "application/zip"),
+    ("rar", "application/vnd.rar"),
+    ("7z", "application/x-7z-compressed"),
+    ("tar", "application/x-tar"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar.gz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("avi", "video/x-msvideo"),
+    ("doc", "application/msword"),
+    ("xls", "application/vnd.ms-excel"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+];
+
+fn extensions() -> &'static RwLock<HashMap<String, MediaType>> {
+    static EXTENSIONS: OnceLock<RwLock<HashMap<String, MediaType>>> =
+        OnceLock::new();
+    EXTENSIONS.get_or_init(|| {
+        let map = BUILTIN_EXTENSIONS
+            .iter()
+            .map(|(ext, media_type)| {
+                (String::from(*ext), media_type.parse().unwrap())
+            })
+            .collect();
+        RwLock::new(map)
+    })
+}
+
+/// Maps files ending in `.ext` to `media_type`, replacing any previous
+/// mapping. `ext` may contain dots, as in `tar.gz`.
+pub fn register_extension(ext: &str, media_type: MediaType) {
+    let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+    extensions().write().unwrap().insert(ext, media_type);
+}
+
+/// Registers the mappings of an `/etc/mime.types` style table, one media
+/// type per line followed by its extensions. Returns how many extensions
+/// were registered.
+pub fn register_mime_types(table: &str) -> usize {
+    let mut count = 0;
+    for line in table.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let media_type: MediaType = match fields.next().map(str::parse) {
+            Some(Ok(media_type)) => media_type,
+            _ => continue,
+        };
+        for ext in fields {
+            register_extension(ext, media_type.clone());
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Loads an `/etc/mime.types` style file with `register_mime_types`.
+pub async fn load_mime_types(path: impl AsRef<Path>) -> io::Result<usize> {
+    let table = tokio::fs::read_to_string(path).await?;
+    Ok(register_mime_types(&table))
+}
+
+/// Looks up the media type registered for the extension of `filename`.
+/// Longer extensions win, so `a.tar.gz` matches `tar.gz` before `gz`.
+pub fn media_type_for(filename: &str) -> Option<MediaType> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    let name = name.to_ascii_lowercase();
+    let map = extensions().read().unwrap();
+    name.match_indices('.')
+        .find_map(|(i, _)| map.get(&name[i + 1..]))
+        .cloned()
+}
+
+/// Media type for `filename`, `application/octet-stream` when unknown.
+pub fn parse_content_type(s: &str) -> MediaType {
+    media_type_for(s)
+        .unwrap_or_else(|| MediaType::new("application", "octet-stream"))
+}
+
+/// How much of a body `Alternative::sniff` reads to guess its type.
+pub const SNIFF_LEN: usize = 8192;
+
+/// Whether `data` looks like UTF-8 text: valid UTF-8 without control
+/// characters other than tab, line breaks, form feed and escape. When
+/// `complete` is unset `data` is a prefix and may end inside a character.
+fn is_text(data: &[u8], complete: bool) -> bool {
+    let valid = match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => !complete && e.error_len().is_none(),
+    };
+    valid
+        && !data.iter().any(
+            |b| matches!(b, 0x00..=0x08 | 0x0e..=0x1a | 0x1c..=0x1f | 0x7f),
+        )
+}
+
+/// Whether `data` can be sent as 7bit: ASCII without NUL or bare CR, in
+/// lines of at most 998 octets.
+fn is_7bit(data: &[u8]) -> bool {
+    data.split(|b| *b == b'\n').all(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        line.len() <= MAX_LINE_LENGTH
+            && line.iter().all(|b| *b != 0 && *b != b'\r' && *b < 0x80)
+    })
+}
+
+/// Guesses a media type from the magic bytes at the start of `data`.
+/// `complete` tells whether `data` is the whole body or only a prefix.
+pub fn sniff_media_type(data: &[u8], complete: bool) -> Option<MediaType> {
+    const MAGIC: &[(&[u8], &str, &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image", "png"),
+        (b"\xff\xd8\xff", "image", "jpeg"),
+        (b"GIF87a", "image", "gif"),
+        (b"GIF89a", "image", "gif"),
+        (b"%PDF-", "application", "pdf"),
+        (b"PK\x03\x04", "application", "zip"),
+        (b"PK\x05\x06", "application", "zip"),
+        (b"\x1f\x8b", "application", "gzip"),
+    ];
+
+    if data.is_empty() {
+        return None;
+    }
+    if let Some((_, type_, subtype)) =
+        MAGIC.iter().find(|(magic, _, _)| data.starts_with(magic))
     {
-        ContentType::TextPlain
+        return Some(MediaType::new(type_, subtype));
+    }
+    if data.starts_with(b"\x7fELF") {
+        // e_type, in the byte order given by EI_DATA.
+        let e_type = match (data.get(5), data.get(16), data.get(17)) {
+            (Some(1), Some(lo), Some(hi)) => {
+                Some(u16::from_le_bytes([*lo, *hi]))
+            }
+            (Some(2), Some(hi), Some(lo)) => {
+                Some(u16::from_be_bytes([*hi, *lo]))
+            }
+            _ => None,
+        };
+        let subtype = match e_type {
+            Some(2) => "x-executable",
+            Some(3) => "x-sharedlib",
+            Some(4) => "x-coredump",
+            _ => "x-elf",
+        };
+        return Some(MediaType::new("application", subtype));
+    }
+    if is_text(data, complete) {
+        return Some(MediaType::new("text", "plain"));
+    }
+    None
+}
+
+/// Picks a transfer encoding for `data`: 7bit for ASCII text, quoted-
+/// printable for mostly ASCII text and base64 for anything else.
+///
+/// 7bit is only chosen when `complete` is set, since a later part of the
+/// body could break it. Quoted-printable takes three octets per non-ASCII
+/// octet where base64 takes 4/3 per octet, so it is chosen while fewer
+/// than one in six octets is non-ASCII.
+pub fn choose_encoding(data: &[u8], complete: bool) -> ContentTransferEncoding {
+    if !is_text(data, complete) {
+        return ContentTransferEncoding::Base64;
+    }
+    if complete && is_7bit(data) {
+        return ContentTransferEncoding::Bit7;
+    }
+    let non_ascii = data.iter().filter(|b| **b >= 0x80).count();
+    if non_ascii * 6 < data.len() {
+        ContentTransferEncoding::QuotedPrintable
     } else {
-        ContentType::ApplicationOctetStream
+        ContentTransferEncoding::Base64
+    }
+}
+
+/// Where the body of an `Alternative` comes from when it is not `content`.
+pub enum BodySource {
+    Bytes(Bytes),
+    /// Read when the message is encoded. The path itself never appears in
+    /// the message.
+    Path(PathBuf),
+    /// Drained when the message is encoded, so it can only be sent once.
+    Reader(Mutex<Box<dyn AsyncRead + Send + Unpin>>),
+}
+
+impl fmt::Debug for BodySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodySource::Bytes(b) => {
+                f.debug_tuple("Bytes").field(&b.len()).finish()
+            }
+            BodySource::Path(p) => f.debug_tuple("Path").field(p).finish(),
+            BodySource::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+/// An open part body.
+enum BodyReader<'a> {
+    Slice(&'a [u8]),
+    Buffer(io::Cursor<Vec<u8>>),
+    File(File),
+    Locked(MutexGuard<'a, Box<dyn AsyncRead + Send + Unpin>>),
+}
+
+impl BodyReader<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BodyReader::Slice(s) => s.read(buf).await,
+            BodyReader::Buffer(b) => b.read(buf).await,
+            BodyReader::File(f) => f.read(buf).await,
+            BodyReader::Locked(r) => r.read(buf).await,
+        }
+    }
+
+    async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match self {
+            BodyReader::Slice(s) => s.read_to_end(buf).await,
+            BodyReader::Buffer(b) => b.read_to_end(buf).await,
+            BodyReader::File(f) => f.read_to_end(buf).await,
+            BodyReader::Locked(r) => r.read_to_end(buf).await,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Alternative {
+    /// Display filename used in the `name` and `filename` parameters.
     pub filename: Option<String>,
     pub content: String,
-    pub content_type: ContentType,
+    /// Body of the part; `content` is used when this is `None`.
+    pub source: Option<BodySource>,
+    /// Charset of text parts, UTF-8 when `None`. `content` is encoded to
+    /// it; bodies from a `source` are expected to be in it already.
+    pub charset: Option<CharSet>,
+    pub content_type: MediaType,
     pub encoding: ContentTransferEncoding,
+    /// Defaults to `Inline` for parts with a Content-ID, `Attachment` for
+    /// other parts with a filename, and no header otherwise.
+    pub disposition: Option<ContentDisposition>,
+    /// Content-ID without angle brackets, referenced from HTML as `cid:...`.
+    pub content_id: Option<String>,
 }
 
-pub async fn mime_encode(
+impl Alternative {
+    /// An attachment read from `path`, named after its last component.
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        Self::attachment(filename, BodySource::Path(path))
+    }
+
+    /// An attachment holding `data`, shown as `filename`. The type is
+    /// sniffed from `data` when the extension is unknown, and the transfer
+    /// encoding is picked with `choose_encoding`.
+    pub fn from_bytes(filename: &str, data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        let mut alternative = Self::attachment(
+            Some(String::from(filename)),
+            BodySource::Bytes(data.clone()),
+        );
+        alternative.detect(&data, true);
+        alternative
+    }
+
+    /// An attachment read from `reader`, shown as `filename`.
+    pub fn from_reader(
+        filename: &str,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+    ) -> Self {
+        Self::attachment(
+            Some(String::from(filename)),
+            BodySource::Reader(Mutex::new(Box::new(reader))),
+        )
+    }
+
+    fn attachment(filename: Option<String>, source: BodySource) -> Self {
+        Self {
+            content_type: parse_content_type(
+                filename.as_deref().unwrap_or_default(),
+            ),
+            filename,
+            content: String::new(),
+            source: Some(source),
+            charset: None,
+            encoding: ContentTransferEncoding::Base64,
+            disposition: None,
+            content_id: None,
+        }
+    }
+
+    /// Reads up to `SNIFF_LEN` bytes of the body to sniff its type when the
+    /// extension is unknown, and to pick its transfer encoding.
+    pub async fn sniff(mut self) -> io::Result<Self> {
+        let (sample, complete) = match self.source.take() {
+            None => (Bytes::from(self.content.clone()), true),
+            Some(BodySource::Bytes(b)) => {
+                self.source = Some(BodySource::Bytes(b.clone()));
+                (b, true)
+            }
+            Some(BodySource::Path(path)) => {
+                let mut sample = Vec::new();
+                File::open(&path)
+                    .await?
+                    .take(SNIFF_LEN as u64 + 1)
+                    .read_to_end(&mut sample)
+                    .await?;
+                self.source = Some(BodySource::Path(path));
+                let complete = sample.len() <= SNIFF_LEN;
+                sample.truncate(SNIFF_LEN);
+                (sample.into(), complete)
+            }
+            Some(BodySource::Reader(reader)) => {
+                // Put the sample back in front of what is left to read.
+                let mut reader = reader.into_inner();
+                let mut sample = Vec::new();
+                (&mut reader)
+                    .take(SNIFF_LEN as u64)
+                    .read_to_end(&mut sample)
+                    .await?;
+                let complete = sample.len() < SNIFF_LEN;
+                let sample = Bytes::from(sample);
+                let reader = io::Cursor::new(sample.clone()).chain(reader);
+                self.source =
+                    Some(BodySource::Reader(Mutex::new(Box::new(reader))));
+                (sample, complete)
+            }
+        };
+        self.detect(&sample, complete);
+        Ok(self)
+    }
+
+    fn detect(&mut self, sample: &[u8], complete: bool) {
+        if self.display_name().and_then(media_type_for).is_none() {
+            if let Some(media_type) = sniff_media_type(sample, complete) {
+                self.content_type = media_type;
+            }
+        }
+        self.encoding = choose_encoding(sample, complete);
+    }
+
+    /// `filename` with any directory components removed.
+    pub fn display_name(&self) -> Option<&str> {
+        self.filename
+            .as_deref()
+            .map(|name| name.rsplit(['/', '\\']).next().unwrap_or(name))
+    }
+
+    /// Opens the raw, unencoded body of the part for reading.
+    async fn reader(&self) -> io::Result<BodyReader<'_>> {
+        Ok(match self.source {
+            None => match self.charset {
+                Some(charset) if charset != CharSet::Utf8 => {
+                    let data =
+                        charset.encode(&self.content).map_err(invalid_data)?;
+                    BodyReader::Buffer(io::Cursor::new(data))
+                }
+                _ => BodyReader::Slice(self.content.as_bytes()),
+            },
+            Some(BodySource::Bytes(ref b)) => BodyReader::Slice(b),
+            Some(BodySource::Path(ref path)) => {
+                BodyReader::File(File::open(path).await?)
+            }
+            Some(BodySource::Reader(ref reader)) => {
+                BodyReader::Locked(reader.lock().await)
+            }
+        })
+    }
+
+    /// Reads the raw, unencoded body of the part.
+    pub async fn body(&self) -> Result<Vec<u8>, ()> {
+        let mut buf = Vec::new();
+        let res = match self.reader().await {
+            Ok(mut reader) => reader.read_to_end(&mut buf).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            return Err(());
+        }
+        Ok(buf)
+    }
+
+    pub fn disposition(&self) -> Option<ContentDisposition> {
+        if self.disposition.is_some() {
+            self.disposition
+        } else if self.content_id.is_some() {
+            Some(ContentDisposition::Inline)
+        } else if self.filename.is_some() {
+            Some(ContentDisposition::Attachment)
+        } else {
+            None
+        }
+    }
+
+    /// Part headers, terminated by the empty line before the body.
+    fn header(&self) -> String {
+        let mut ct = self.content_type.clone();
+        if let Some(filename) = self.display_name() {
+            ct = ct.with_param("name", filename);
+        }
+        let ec = ContentTransferEncoding::VALUE_MAP[self.encoding as usize];
+        let mut header =
+            format!("Content-Type: {}\r\n", with_charset(ct, self.charset));
+        header.push_str(&format!("Content-Transfer-Encoding: {}\r\n", ec));
+        if let Some(disposition) = self.disposition() {
+            let cd = ContentDisposition::VALUE_MAP[disposition as usize];
+            match self.display_name() {
+                Some(filename) => header.push_str(&format!(
+                    "Content-Disposition: {}; filename={}\r\n",
+                    cd,
+                    quote_param(filename)
+                )),
+                None => {
+                    header.push_str(&format!("Content-Disposition: {}\r\n", cd))
+                }
+            }
+        }
+        if let Some(ref id) = self.content_id {
+            // Only characters that can appear inside `<...>` are kept.
+            let id: String = id
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .chars()
+                .filter(|c| c.is_ascii_graphic() && !"<>\"\\".contains(*c))
+                .collect();
+            header.push_str(&format!("Content-ID: <{}>\r\n", id));
+        }
+        header.push_str("\r\n");
+        header
+    }
+}
+
+/// Headers of an outgoing message beyond From, To and Subject. `Date` and
+/// `Message-ID` are generated when not set; a value that is set is checked
+/// and used as is.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    /// Domain of generated Message-IDs, `localhost` if unset.
+    pub host: Option<String>,
+    pub date: Option<String>,
+    pub message_id: Option<String>,
+    /// Generate `Date` with a UTC offset instead of the local one.
+    pub utc: bool,
+    /// Address list of carbon copy recipients.
+    pub cc: Option<String>,
+    /// Message-ID of the message replied to.
+    pub in_reply_to: Option<String>,
+    /// Message-IDs of the thread, oldest first.
+    pub references: Vec<String>,
+}
+
+impl Headers {
+    /// Returns a copy with `Date` and `Message-ID` filled in, or an error if
+    /// an overridden one is malformed. Resolving twice keeps the values.
+    pub fn resolve(&self) -> Result<Headers, &'static str> {
+        let date = match &self.date {
+            Some(date) => {
+                if DateTime::parse_from_rfc2822(date).is_err() {
+                    return Err("invalid Date header");
+                }
+                date.clone()
+            }
+            None if self.utc => Utc::now().to_rfc2822(),
+            None => Local::now().to_rfc2822(),
+        };
+        let message_id = match &self.message_id {
+            Some(id) => {
+                if !is_msg_id(id) {
+                    return Err("invalid Message-ID header");
+                }
+                id.clone()
+            }
+            None => {
+                let host = self.host.as_deref().unwrap_or("localhost");
+                let id = generate_message_id(host);
+                if !is_msg_id(&id) {
+                    return Err("invalid Message-ID host");
+                }
+                id
+            }
+        };
+        if let Some(ref cc) = self.cc {
+            parse_address_list(cc)?;
+        }
+        let thread = self.in_reply_to.iter().chain(&self.references);
+        if !thread.into_iter().all(|id| is_msg_id(id)) {
+            return Err("invalid In-Reply-To or References header");
+        }
+        Ok(Headers {
+            date: Some(date),
+            message_id: Some(message_id),
+            ..self.clone()
+        })
+    }
+}
+
+/// `<left@right>` with no whitespace, brackets or further `@` inside.
+pub(crate) fn is_msg_id(id: &str) -> bool {
+    let inner = match id.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+        Some(inner) => inner,
+        None => return false,
+    };
+    let ok = |s: &str| {
+        !s.is_empty()
+            && s.bytes().all(|b| {
+                b.is_ascii_graphic() && !b"<>@\"\\()[],;:".contains(&b)
+            })
+    };
+    match inner.split_once('@') {
+        Some((left, right)) => ok(left) && ok(right),
+        None => false,
+    }
+}
+
+/// Unique per process by the counter, across processes and hosts by the
+/// time, pid and random part.
+fn generate_message_id(host: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(now);
+    hasher.write_u64(count);
+    format!(
+        "<{:x}.{:x}.{:x}.{:x}@{}>",
+        now,
+        std::process::id(),
+        count,
+        hasher.finish(),
+        host
+    )
+}
+
+/// Multipart boundary of the message with `message_id`, so a message
+/// forwarded inside another has a different one. `=_` cannot occur in
+/// base64 or quoted-printable text (RFC 2045).
+fn boundary(message_id: &str) -> String {
+    let digest = Md5::digest(message_id.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("=_{}", hex)
+}
+
+/// Parses From and To, which cannot be left out of a message. From names
+/// mailboxes only, To may also hold groups.
+pub(crate) fn required_addresses(
+    from: &str,
+    to: &str,
+) -> Result<(Vec<Address>, Vec<Address>), &'static str> {
+    if from.trim().is_empty() {
+        return Err("missing From header");
+    }
+    if to.trim().is_empty() {
+        return Err("missing To header");
+    }
+    let from = parse_address_list(from)?;
+    if from.iter().any(|a| matches!(a, Address::Group(..))) {
+        return Err("group in From header");
+    }
+    Ok((from, parse_address_list(to)?))
+}
+
+/// Adds the charset, UTF-8 by default, to text types that do not name one.
+fn with_charset(media_type: MediaType, charset: Option<CharSet>) -> MediaType {
+    if media_type.is_text() && media_type.param("charset").is_none() {
+        let charset = charset.unwrap_or(CharSet::Utf8);
+        media_type.with_param("charset", CharSet::VALUE_MAP[charset as usize])
+    } else {
+        media_type
+    }
+}
+
+/// Message headers up to the first boundary of a multipart message, or up
+/// to and including the blank line before the body of a single part one.
+pub(crate) fn message_header(
     from: &str,
     to: &str,
     subject: &str,
     content_transfer_encoding: ContentTransferEncoding,
-    content_type: ContentType,
-    content: &str,
+    content_type: &MediaType,
     attach: Option<&[Alternative]>,
-) -> Result<Vec<u8>, ()> {
-    let mut encoded = Vec::new();
-
+    headers: &Headers,
+) -> Result<(String, Option<String>), &'static str> {
     let ec =
         ContentTransferEncoding::VALUE_MAP[content_transfer_encoding as usize];
-    let ct = ContentType::VALUE_MAP[content_type as usize];
 
-    for b in format!(
-        "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n",
-        from, to, subject
-    )
-    .as_bytes()
-    {
-        encoded.put_u8(*b);
+    let (from, to) = required_addresses(from, to)?;
+    let headers = headers.resolve()?;
+    let mut header = format!(
+        "Date: {}\r\nFrom: {}\r\nTo: {}\r\n",
+        headers.date.unwrap_or_default(),
+        format_address_list(&from),
+        format_address_list(&to),
+    );
+    if let Some(ref cc) = headers.cc {
+        let cc = format_address_list(&parse_address_list(cc)?);
+        header.push_str(&format!("Cc: {}\r\n", cc));
+    }
+    header.push_str(&format!(
+        "Subject: {}\r\nMessage-ID: {}\r\n",
+        encode_header(subject),
+        headers.message_id.as_deref().unwrap_or_default()
+    ));
+    if let Some(ref id) = headers.in_reply_to {
+        header.push_str(&format!("In-Reply-To: {}\r\n", id));
     }
+    if !headers.references.is_empty() {
+        // One Message-ID per line keeps long threads within line limits.
+        let references = headers.references.join("\r\n ");
+        header.push_str(&format!("References: {}\r\n", references));
+    }
+    header.push_str("MIME-Version: 1.0\r\n");
 
-    let multipart = match content_type {
-        ContentType::MultipartMixed | ContentType::MultipartAlternative => {
-            for b in
-                format!("Content-Type: {}; boundary=\"0123456789\"\r\n", ct)
-                    .as_bytes()
-            {
-                encoded.put_u8(*b);
+    let multipart = content_type
+        .is_multipart()
+        .then(|| boundary(headers.message_id.as_deref().unwrap_or_default()));
+    if let Some(boundary) = &multipart {
+        let mut ct = content_type.clone().with_param("boundary", boundary);
+        // RFC 2387: `type` names the root part, which comes first.
+        if ct.subtype() == "related" && ct.param("type").is_none() {
+            match attach.and_then(|a| a.first()) {
+                Some(root) => {
+                    ct = ct.with_param("type", &root.content_type.essence())
+                }
+                None => return Err("attach not found"),
             }
-
-            true
         }
-        _ => {
-            for b in format!("Content-Type: {}; charset=\"utf-8\"\r\n", ct)
-                .as_bytes()
-            {
-                encoded.put_u8(*b);
-            }
+        header.push_str(&format!("Content-Type: {}\r\n", ct));
+    } else {
+        header.push_str(&format!(
+            "Content-Type: {}\r\nContent-Transfer-Encoding: {}\r\n\r\n",
+            with_charset(content_type.clone(), None),
+            ec
+        ));
+    }
 
-            false
-        }
-    };
+    if multipart.is_some() && attach.is_none() {
+        return Err("attach not found");
+    }
+    Ok((header, multipart))
+}
 
-    if multipart {
-        if attach.is_none() {
-            eprintln!("attach not found");
+/// Encodes a whole message in memory. Use `MimeEncoder` to stream large
+/// attachments instead.
+#[allow(clippy::too_many_arguments)]
+pub async fn mime_encode(
+    from: &str,
+    to: &str,
+    subject: &str,
+    content_transfer_encoding: ContentTransferEncoding,
+    content_type: impl Into<MediaType>,
+    content: &str,
+    attach: Option<&[Alternative]>,
+    headers: Option<&Headers>,
+) -> Result<Vec<u8>, ()> {
+    let (header, multipart) = match message_header(
+        from,
+        to,
+        subject,
+        content_transfer_encoding,
+        &content_type.into(),
+        attach,
+        headers.unwrap_or(&Headers::default()),
+    ) {
+        Ok(h) => h,
+        Err(e) => {
+            eprintln!("{}", e);
             return Err(());
         }
-        for elts in attach.unwrap() {
-            let ec = ContentTransferEncoding::VALUE_MAP[elts.encoding as usize];
-            let ct = ContentType::VALUE_MAP[elts.content_type as usize];
-            if let Some(ref filename) = elts.filename {
-                for b in format!("\r\n--0123456789\r\nContent-Type: {}; name=\"{}\"; charset=\"utf-8\"\r\nContent-Transfer-Encoding: base64\r\n\r\n", ct, filename).as_bytes() {
-                    encoded.put_u8(*b);
+    };
+    let mut encoded = header.into_bytes();
+
+    if let Some(boundary) = multipart {
+        for elts in attach.unwrap_or_default() {
+            let body = elts.body().await?;
+            let delimiter = format!("\r\n--{}\r\n", boundary);
+            encoded.extend_from_slice(delimiter.as_bytes());
+            encoded.extend_from_slice(elts.header().as_bytes());
+            match elts.encoding.encode(&body) {
+                Ok(mut v) => encoded.append(&mut v),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Err(());
                 }
-                match File::open(filename).await {
-                    Ok(mut file) => {
-                        let mut buf = Vec::new();
-                        file.read_to_end(&mut buf).await.unwrap();
-                        for b in encode(&buf).as_bytes() {
-                            encoded.put_u8(*b);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(());
+            }
+        }
+        let close = format!("\r\n--{}--\r\n", boundary);
+        encoded.extend_from_slice(close.as_bytes());
+    } else {
+        match content_transfer_encoding.encode(content.as_bytes()) {
+            Ok(mut v) => encoded.append(&mut v),
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(());
+            }
+        }
+    }
+
+    Ok(encoded)
+}
+
+/// Raw input read per chunk. A multiple of 57 so every base64 chunk ends on
+/// a full 76 column line.
+const STREAM_CHUNK: usize = 57 * 64;
+
+/// Longest run without a line break that quoted-printable buffers before
+/// splitting it with a soft line break.
+const STREAM_MAX_LINE: usize = 16 * STREAM_CHUNK;
+
+/// Encodes one part body chunk by chunk.
+struct PartEncoder<'a> {
+    reader: BodyReader<'a>,
+    encoding: ContentTransferEncoding,
+    /// Input read but not encoded yet.
+    carry: Vec<u8>,
+    /// Output column, for quoted-printable lines spanning chunks.
+    col: usize,
+    first: bool,
+}
+
+impl PartEncoder<'_> {
+    /// Reads and encodes the next chunk, `None` once the body is exhausted.
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut buf = vec![0; STREAM_CHUNK];
+        let mut chunk = Vec::new();
+        match self.encoding {
+            ContentTransferEncoding::Base64 => {
+                let mut n = 0;
+                while n < STREAM_CHUNK {
+                    match self.reader.read(&mut buf[n..]).await? {
+                        0 => break,
+                        m => n += m,
                     }
                 }
-            } else {
-                for b in format!("\r\n--0123456789\r\nContent-Type: {}; charset=\"utf-8\"\r\nContent-Transfer-Encoding: {}\r\n\r\n", ct, ec).as_bytes() {
-                    encoded.put_u8(*b);
+                if n == 0 {
+                    return Ok(None);
                 }
-                match elts.encoding {
-                    ContentTransferEncoding::Base64 => {
-                        for b in encode(&elts.content).as_bytes() {
-                            encoded.put_u8(*b);
-                        }
-                    }
-                    ContentTransferEncoding::Bit7 => match encoding::all::ASCII
-                        .encode(&elts.content, EncoderTrap::Strict)
+                if !self.first {
+                    chunk.extend_from_slice(b"\r\n");
+                }
+                chunk.append(&mut base64_encode(&buf[..n]));
+            }
+            ContentTransferEncoding::Bit7
+            | ContentTransferEncoding::Bit8
+            | ContentTransferEncoding::QuotedPrintable => {
+                // Encode up to the last complete line. Without one, keep
+                // reading until the line is too long for 7bit or 8bit, CRLF
+                // included, or encode all but the last two bytes of it as
+                // quoted-printable.
+                let qp = matches!(
+                    self.encoding,
+                    ContentTransferEncoding::QuotedPrintable
+                );
+                let limit = match qp {
+                    true => STREAM_MAX_LINE,
+                    false => MAX_LINE_LENGTH + 2,
+                };
+                let end = loop {
+                    if let Some(pos) =
+                        self.carry.iter().rposition(|b| *b == b'\n')
                     {
-                        Ok(mut v) => {
-                            encoded.append(&mut v);
-                        }
-                        Err(e) => {
-                            eprintln!("{}", e);
-                            return Err(());
-                        }
-                    },
-                    ContentTransferEncoding::QuotedPrintable => {
-                        encoded.append(&mut quoted_printable::encode(
-                            &elts.content,
-                        ));
+                        break pos + 1;
                     }
-                }
+                    if self.carry.len() >= limit {
+                        break match qp {
+                            true => self.carry.len() - 2,
+                            false => self.carry.len(),
+                        };
+                    }
+                    match self.reader.read(&mut buf).await? {
+                        0 if self.carry.is_empty() => return Ok(None),
+                        0 => break self.carry.len(),
+                        n => self.carry.extend_from_slice(&buf[..n]),
+                    }
+                };
+                chunk = match qp {
+                    true => qp_encode_prefix(&self.carry, end, &mut self.col),
+                    false => self
+                        .encoding
+                        .encode(&self.carry[..end])
+                        .map_err(invalid_data)?,
+                };
+                self.carry.drain(..end);
             }
         }
-        for b in b"\r\n--0123456789--\r\n" {
-            encoded.put_u8(*b);
-        }
-    } else {
-        match content_transfer_encoding {
-            ContentTransferEncoding::Base64 => {
-                for b in format!("Content-Transfer-Encoding: {}\r\n\r\n", ec)
-                    .as_bytes()
-                {
-                    encoded.put_u8(*b);
-                }
-                for b in encode(content).as_bytes() {
-                    encoded.put_u8(*b);
+        self.first = false;
+        Ok(Some(chunk.into()))
+    }
+}
+
+enum EncodeStage<'a> {
+    Header,
+    Part(usize),
+    Body(usize, PartEncoder<'a>),
+    Close,
+    Done,
+}
+
+struct EncodeState<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    content_transfer_encoding: ContentTransferEncoding,
+    content_type: MediaType,
+    content: &'a str,
+    attach: &'a [Alternative],
+    headers: Headers,
+    /// Set once the header of a multipart message is out.
+    boundary: String,
+    stage: EncodeStage<'a>,
+}
+
+fn invalid_data(e: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl EncodeState<'_> {
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            match self.stage {
+                EncodeStage::Header => {
+                    let (header, multipart) = message_header(
+                        self.from,
+                        self.to,
+                        self.subject,
+                        self.content_transfer_encoding,
+                        &self.content_type,
+                        Some(self.attach),
+                        &self.headers,
+                    )
+                    .map_err(invalid_data)?;
+                    let mut chunk = header.into_bytes();
+                    if let Some(boundary) = multipart {
+                        self.boundary = boundary;
+                        self.stage = EncodeStage::Part(0);
+                    } else {
+                        let mut body = self
+                            .content_transfer_encoding
+                            .encode(self.content.as_bytes())
+                            .map_err(invalid_data)?;
+                        chunk.append(&mut body);
+                        self.stage = EncodeStage::Done;
+                    }
+                    return Ok(Some(chunk.into()));
                 }
-            }
-            ContentTransferEncoding::Bit7 => {
-                for b in format!("Content-Transfer-Encoding: {}\r\n\r\n", ec)
-                    .as_bytes()
-                {
-                    encoded.put_u8(*b);
+                EncodeStage::Part(i) => {
+                    let elts = match self.attach.get(i) {
+                        Some(elts) => elts,
+                        None => {
+                            self.stage = EncodeStage::Close;
+                            continue;
+                        }
+                    };
+                    let part = PartEncoder {
+                        reader: elts.reader().await?,
+                        encoding: elts.encoding,
+                        carry: Vec::new(),
+                        col: 0,
+                        first: true,
+                    };
+                    self.stage = EncodeStage::Body(i, part);
+                    let header =
+                        format!("\r\n--{}\r\n{}", self.boundary, elts.header());
+                    return Ok(Some(header.into()));
                 }
-                match encoding::all::ASCII.encode(content, EncoderTrap::Strict)
-                {
-                    Ok(mut v) => encoded.append(&mut v),
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(());
+                EncodeStage::Body(i, ref mut part) => {
+                    match part.next_chunk().await? {
+                        Some(chunk) => return Ok(Some(chunk)),
+                        None => self.stage = EncodeStage::Part(i + 1),
                     }
                 }
-            }
-            ContentTransferEncoding::QuotedPrintable => {
-                for b in format!("Content-Transfer-Encoding: {}\r\n\r\n", ec)
-                    .as_bytes()
-                {
-                    encoded.put_u8(*b);
+                EncodeStage::Close => {
+                    self.stage = EncodeStage::Done;
+                    let close = format!("\r\n--{}--\r\n", self.boundary);
+                    return Ok(Some(close.into()));
                 }
-                encoded.append(&mut quoted_printable::encode(content));
+                EncodeStage::Done => return Ok(None),
             }
         }
     }
+}
 
-    Ok(encoded)
+/// Streams an encoded message, reading and encoding attachments chunk by
+/// chunk so memory use does not grow with their size.
+///
+/// Given the same resolved `Headers`, the output is identical to
+/// `mime_encode`. It can be consumed either as a `Stream` of chunks or
+/// through `AsyncRead`.
+pub struct MimeEncoder<'a> {
+    stream: BoxStream<'a, io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl<'a> MimeEncoder<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        from: &'a str,
+        to: &'a str,
+        subject: &'a str,
+        content_transfer_encoding: ContentTransferEncoding,
+        content_type: impl Into<MediaType>,
+        content: &'a str,
+        attach: Option<&'a [Alternative]>,
+        headers: Option<&Headers>,
+    ) -> Self {
+        let state = EncodeState {
+            from,
+            to,
+            subject,
+            content_transfer_encoding,
+            content_type: content_type.into(),
+            content,
+            attach: attach.unwrap_or_default(),
+            headers: headers.cloned().unwrap_or_default(),
+            boundary: String::new(),
+            stage: EncodeStage::Header,
+        };
+        let stream = stream::try_unfold(state, |mut state| async move {
+            Ok(state.next_chunk().await?.map(|chunk| (chunk, state)))
+        });
+        Self {
+            stream: stream.boxed(),
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Stream for MimeEncoder<'_> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if !this.chunk.is_empty() {
+            return Poll::Ready(Some(Ok(std::mem::take(&mut this.chunk))));
+        }
+        this.stream.poll_next_unpin(cx)
+    }
+}
+
+impl AsyncRead for MimeEncoder<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.chunk.is_empty() {
+            match ready!(this.stream.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => this.chunk = chunk,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = buf.remaining().min(this.chunk.len());
+        buf.put_slice(&this.chunk.split_to(n));
+        Poll::Ready(Ok(()))
+    }
 }
 
 pub async fn mime_decode(content: &str) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64_wraps_at_76() {
+        let out = base64_encode(&[0xa5; 1000]);
+        let lines: Vec<&[u8]> = out.split(|b| *b == b'\n').collect();
+        assert!(lines.len() > 1);
+        for line in &lines[..lines.len() - 1] {
+            assert_eq!(line.len(), ENCODED_LINE_LENGTH + 1);
+            assert!(line.ends_with(b"\r"));
+        }
+        let joined: Vec<u8> = out
+            .iter()
+            .copied()
+            .filter(|b| *b != b'\r' && *b != b'\n')
+            .collect();
+        assert_eq!(base64::decode(&joined).unwrap(), vec![0xa5; 1000]);
+    }
+
+    #[test]
+    fn qp_soft_breaks() {
+        let input = "x".repeat(200) + "\n" + &"=".repeat(40);
+        let out = qp_encode(input.as_bytes());
+        for line in out.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            assert!(line.len() <= ENCODED_LINE_LENGTH);
+        }
+        let decoded =
+            quoted_printable::decode(&out, quoted_printable::ParseMode::Strict)
+                .unwrap();
+        assert_eq!(decoded, input.replace('\n', "\r\n").as_bytes());
+    }
+
+    #[test]
+    fn qp_trailing_whitespace_and_dots() {
+        assert_eq!(qp_encode(b"a \r\nb\t"), b"a=20\r\nb=09");
+        // Dots are stuffed by SMTP, like those of 7bit text.
+        assert_eq!(qp_encode(b".\n.."), b".\r\n..");
+        assert_eq!(qp_encode("é".as_bytes()), b"=C3=A9");
+    }
+
+    #[test]
+    fn bit7_validation() {
+        assert_eq!(bit7_encode(b"a\nb\r\n").unwrap(), b"a\r\nb\r\n");
+        assert!(bit7_encode("é".as_bytes()).is_err());
+        assert!(bit7_encode(b"a\0b").is_err());
+        assert!(bit7_encode(b"a\rb").is_err());
+        assert!(bit7_encode("z".repeat(998).as_bytes()).is_ok());
+        assert!(bit7_encode("z".repeat(999).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn bit8_validation() {
+        assert_eq!(bit8_encode("é\n".as_bytes()).unwrap(), "é\r\n".as_bytes());
+        assert!(bit8_encode(b"a\0b").is_err());
+        assert!(bit8_encode(b"a\rb").is_err());
+        assert!(bit8_encode("z".repeat(999).as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn attachment_honours_encoding() {
+        let attach = [
+            Alternative {
+                encoding: ContentTransferEncoding::QuotedPrintable,
+                ..Alternative::from_path("Cargo.toml")
+            },
+            Alternative::from_path("bear2.jpg"),
+        ];
+        let out = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "test",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&attach),
+            None,
+        )
+        .await
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(
+            "name=\"Cargo.toml\"; charset=\"utf-8\"\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n"
+        ));
+        for line in text.split("\r\n") {
+            assert!(line.len() <= ENCODED_LINE_LENGTH, "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn related_inline_image() {
+        let attach = [
+            Alternative {
+                filename: None,
+                content: String::from("<img src=\"cid:graph@jckeep.top\">"),
+                source: None,
+                charset: None,
+                content_type: ContentType::TextHtml.into(),
+                encoding: ContentTransferEncoding::QuotedPrintable,
+                disposition: None,
+                content_id: None,
+            },
+            Alternative {
+                content_id: Some(String::from("graph@jckeep.top")),
+                ..Alternative::from_path("bear2.jpg")
+            },
+        ];
+        let out = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "graphs",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartRelated,
+            "",
+            Some(&attach),
+            None,
+        )
+        .await
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let start = text.find("Content-Type: multipart/related").unwrap();
+        let line = &text[start..start + text[start..].find("\r\n").unwrap()];
+        assert!(line.contains("; boundary=\"=_"), "{}", line);
+        assert!(line.ends_with("; type=\"text/html\""), "{}", line);
+        assert!(text.contains(
+            "Content-Disposition: inline; filename=\"bear2.jpg\"\r\n\
+             Content-ID: <graph@jckeep.top>\r\n\r\n"
+        ));
+        let html = text.find("cid:graph").unwrap();
+        let img = text.find("Content-ID").unwrap();
+        assert!(html < img);
+    }
+
+    #[test]
+    fn default_disposition() {
+        let mut part = Alternative::from_bytes("report.pdf", vec![]);
+        assert!(part.header().contains(
+            "Content-Disposition: attachment; filename=\"report.pdf\"\r\n"
+        ));
+        part.disposition = Some(ContentDisposition::Inline);
+        assert!(part.header().contains("Content-Disposition: inline;"));
+        part.filename = None;
+        part.disposition = None;
+        assert!(!part.header().contains("Content-Disposition"));
+    }
+
+    #[tokio::test]
+    async fn attachment_sources() {
+        let attach = [
+            Alternative::from_path("src/main.rs"),
+            Alternative::from_bytes("graph.png", vec![0x89, b'P', b'N', b'G']),
+            Alternative::from_reader("log.txt", &b"reader body"[..]),
+            Alternative {
+                filename: Some(String::from("renamed.toml")),
+                ..Alternative::from_path("./Cargo.toml")
+            },
+        ];
+        let out = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "sources",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&attach),
+            None,
+        )
+        .await
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(!text.contains("src/"));
+        assert!(!text.contains("Cargo.toml"));
+        assert!(text.contains("filename=\"main.rs\""));
+        assert!(text.contains("filename=\"renamed.toml\""));
+        assert!(text.contains("Content-Type: image/png; name=\"graph.png\""));
+        assert!(text.contains(&encode("reader body")));
+        assert!(text.contains(&encode([0x89, b'P', b'N', b'G'])));
+    }
+
+    #[test]
+    fn display_name_strips_directories() {
+        let part = Alternative::from_bytes("/home/me/logs\\a.log", vec![]);
+        assert_eq!(part.display_name(), Some("a.log"));
+    }
+
+    fn fixed_headers() -> Headers {
+        Headers {
+            date: Some(String::from("Mon, 19 Oct 2026 08:30:00 +0800")),
+            message_id: Some(String::from("<1.2@example.com>")),
+            ..Headers::default()
+        }
+    }
+
+    async fn stream_encode(attach: &[Alternative]) -> io::Result<Vec<u8>> {
+        let mut encoder = MimeEncoder::new(
+            "a@example.com",
+            "b@example.com",
+            "stream",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(attach),
+            Some(&fixed_headers()),
+        );
+        let mut out = Vec::new();
+        encoder.read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    fn streamed_parts() -> Vec<Alternative> {
+        let long_line = "q".repeat(STREAM_MAX_LINE + 100) + " \n";
+        let lines = "line of text \n".repeat(2000);
+        let binary: Vec<u8> = (0..STREAM_CHUNK * 3 + 17)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect();
+        vec![
+            Alternative::from_bytes("binary.bin", binary),
+            Alternative::from_path("bear2.jpg"),
+            Alternative {
+                encoding: ContentTransferEncoding::QuotedPrintable,
+                ..Alternative::from_bytes("long.txt", long_line.into_bytes())
+            },
+            Alternative {
+                encoding: ContentTransferEncoding::QuotedPrintable,
+                ..Alternative::from_bytes("lines.txt", lines.clone())
+            },
+            Alternative {
+                encoding: ContentTransferEncoding::Bit7,
+                ..Alternative::from_reader(
+                    "lines.log",
+                    std::io::Cursor::new(lines),
+                )
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn stream_matches_buffered() {
+        let streamed = stream_encode(&streamed_parts()).await.unwrap();
+        let buffered = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "stream",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&streamed_parts()),
+            Some(&fixed_headers()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(streamed.len(), buffered.len());
+        assert!(streamed == buffered);
+    }
+
+    #[tokio::test]
+    async fn stream_chunks_are_bounded() {
+        let attach = [Alternative::from_bytes("big.bin", vec![0u8; 1 << 20])];
+        let mut encoder = MimeEncoder::new(
+            "a@example.com",
+            "b@example.com",
+            "big",
+            ContentTransferEncoding::Base64,
+            ContentType::MultipartMixed,
+            "",
+            Some(&attach),
+            None,
+        );
+        let mut chunks = 0;
+        while let Some(chunk) = encoder.next().await {
+            assert!(chunk.unwrap().len() <= STREAM_CHUNK * 2);
+            chunks += 1;
+        }
+        assert!(chunks > (1 << 20) / STREAM_CHUNK);
+    }
+
+    #[tokio::test]
+    async fn stream_reports_invalid_7bit() {
+        let attach = [Alternative {
+            encoding: ContentTransferEncoding::Bit7,
+            ..Alternative::from_bytes("long.txt", "z".repeat(2000))
+        }];
+        let err = stream_encode(&attach).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn stream_7bit_line_split_before_lf() {
+        // The longest legal line, with the read ending between CR and LF.
+        let line = "z".repeat(MAX_LINE_LENGTH);
+        let first = std::io::Cursor::new(format!("{}\r", line).into_bytes());
+        let rest = std::io::Cursor::new(b"\nnext\r\n".to_vec());
+        let attach = [Alternative {
+            encoding: ContentTransferEncoding::Bit7,
+            ..Alternative::from_reader("max.txt", first.chain(rest))
+        }];
+        let out = stream_encode(&attach).await.unwrap();
+        let body = format!("\r\n{}\r\nnext\r\n", line);
+        assert!(out.windows(body.len()).any(|w| w == body.as_bytes()));
+    }
+
+    #[test]
+    fn media_type_parse_and_display() {
+        let mt: MediaType = "Text/HTML; Charset=\"utf-8\"; format=flowed"
+            .parse()
+            .unwrap();
+        assert_eq!(mt.essence(), "text/html");
+        assert_eq!(mt.param("charset"), Some("utf-8"));
+        assert_eq!(mt.param("FORMAT"), Some("flowed"));
+        assert_eq!(
+            mt.to_string(),
+            "text/html; charset=\"utf-8\"; format=\"flowed\""
+        );
+        let mt: MediaType =
+            "application/x-a; name=\"a \\\"b\\\";c\"".parse().unwrap();
+        assert_eq!(mt.param("name"), Some("a \"b\";c"));
+        assert_eq!(mt.to_string().parse::<MediaType>().unwrap(), mt);
+        assert!("text".parse::<MediaType>().is_err());
+        assert!("text/plain; a=\"b".parse::<MediaType>().is_err());
+        assert!("text/pl ain".parse::<MediaType>().is_err());
+    }
+
+    #[test]
+    fn builtin_extensions() {
+        let essence = |name: &str| parse_content_type(name).essence();
+        assert_eq!(essence("report.xls"), "application/vnd.ms-excel");
+        assert_eq!(essence("voice.M4A"), "audio/mp4");
+        assert_eq!(essence("data.json"), "application/json");
+        assert_eq!(essence("graph.svg"), "image/svg+xml");
+        assert_eq!(essence("logs.tar.gz"), "application/gzip");
+        assert_eq!(essence("invite.ics"), "text/calendar");
+        assert_eq!(essence("dir.d/core"), "application/octet-stream");
+        assert_eq!(essence("noext"), "application/octet-stream");
+    }
+
+    #[test]
+    fn registered_extensions() {
+        register_extension(".Sarif", "application/sarif+json".parse().unwrap());
+        assert_eq!(
+            media_type_for("scan.sarif").unwrap().essence(),
+            "application/sarif+json"
+        );
+        let table = "# comment\n\
+                     application/x-test-a\ttesta testaa\n\
+                     application/x-test-b\n\
+                     bogus testc\n";
+        assert_eq!(register_mime_types(table), 2);
+        assert_eq!(
+            media_type_for("x.testaa").unwrap().essence(),
+            "application/x-test-a"
+        );
+        assert!(media_type_for("x.testc").is_none());
+    }
+
+    #[test]
+    fn sniff_magic_bytes() {
+        let essence =
+            |data: &[u8]| sniff_media_type(data, true).map(|m| m.essence());
+        assert_eq!(essence(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png".into()));
+        assert_eq!(essence(b"\xff\xd8\xff\xe0"), Some("image/jpeg".into()));
+        assert_eq!(essence(b"GIF89a\x01\0"), Some("image/gif".into()));
+        assert_eq!(essence(b"%PDF-1.7\n"), Some("application/pdf".into()));
+        assert_eq!(
+            essence(b"PK\x03\x04\x14\0"),
+            Some("application/zip".into())
+        );
+        assert_eq!(essence(b"\x1f\x8b\x08\0"), Some("application/gzip".into()));
+        let mut core = b"\x7fELF\x02\x01\x01\0".to_vec();
+        core.resize(16, 0);
+        core.extend_from_slice(&[4, 0]);
+        assert_eq!(essence(&core), Some("application/x-coredump".into()));
+        assert_eq!(
+            essence("Oct 19 kernel: 日志\n".as_bytes()),
+            Some("text/plain".into())
+        );
+        assert_eq!(essence(b"\0\x01\x02\x03"), None);
+        assert_eq!(essence(b""), None);
+
+        // A prefix may end in the middle of a character.
+        let text = "日志".as_bytes();
+        assert!(sniff_media_type(&text[..4], true).is_none());
+        assert!(sniff_media_type(&text[..4], false).is_some());
+    }
+
+    #[test]
+    fn choose_transfer_encoding() {
+        let kind = |data: &[u8], complete| {
+            ContentTransferEncoding::VALUE_MAP
+                [choose_encoding(data, complete) as usize]
+        };
+        assert_eq!(kind(b"plain ascii\r\nlines\n", true), "7bit");
+        assert_eq!(kind(b"plain ascii\r\nlines\n", false), "quoted-printable");
+        assert_eq!(kind("x".repeat(1000).as_bytes(), true), "quoted-printable");
+        assert_eq!(
+            kind("mostly ascii, café".as_bytes(), true),
+            "quoted-printable"
+        );
+        assert_eq!(kind("传输层协议".as_bytes(), true), "base64");
+        assert_eq!(kind(b"\x89PNG\r\n\x1a\n", true), "base64");
+    }
+
+    #[tokio::test]
+    async fn sniff_attachments() {
+        let path = std::env::temp_dir().join("email-sniff-syslog.1");
+        std::fs::write(&path, "Oct 19 12:00:00 host cron[1]: ok\n").unwrap();
+        let part = Alternative::from_path(&path).sniff().await.unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(part.content_type.essence(), "text/plain");
+        assert!(matches!(part.encoding, ContentTransferEncoding::Bit7));
+
+        // A known extension wins over the content.
+        let part = Alternative::from_bytes("data.json", "{}");
+        assert_eq!(part.content_type.essence(), "application/json");
+        assert!(matches!(part.encoding, ContentTransferEncoding::Bit7));
+
+        let data = b"%PDF-1.4\n\xe2\xe3\xcf\xd3".repeat(SNIFF_LEN);
+        let part = Alternative::from_reader(
+            "report",
+            std::io::Cursor::new(data.clone()),
+        )
+        .sniff()
+        .await
+        .unwrap();
+        assert_eq!(part.content_type.essence(), "application/pdf");
+        assert!(matches!(part.encoding, ContentTransferEncoding::Base64));
+        assert_eq!(part.body().await.unwrap(), data);
+    }
+
+    #[test]
+    fn charset_round_trip() {
+        let cases = [
+            (CharSet::Gbk, "传输层协议"),
+            (CharSet::Gb18030, "传输层协议"),
+            (CharSet::Big5, "傳輸層協議"),
+            (CharSet::ShiftJis, "メールの件名"),
+            (CharSet::EucJp, "メールの件名"),
+            (CharSet::Iso2022Jp, "メールの件名"),
+            (CharSet::EucKr, "메일 제목"),
+            (CharSet::Iso8859_1, "café"),
+        ];
+        for (charset, text) in cases {
+            let encoded = charset.encode(text).unwrap();
+            assert_ne!(encoded, text.as_bytes());
+            assert_eq!(charset.decode(&encoded), text);
+        }
+        assert!(CharSet::Iso8859_1.encode("传输").is_err());
+        assert_eq!(CharSet::Gbk.decode(b"\xb4\xab\xff"), "传\u{fffd}");
+    }
+
+    #[test]
+    fn charset_labels() {
+        assert_eq!(CharSet::from_label("GBK"), Some(CharSet::Gbk));
+        assert_eq!(CharSet::from_label("cp936"), Some(CharSet::Gbk));
+        assert_eq!(CharSet::from_label("Shift_JIS"), Some(CharSet::ShiftJis));
+        assert_eq!(CharSet::from_label("sjis"), Some(CharSet::ShiftJis));
+        assert_eq!(CharSet::from_label("x-gbk"), Some(CharSet::Gbk));
+        assert_eq!(CharSet::from_label("ISO-8859-1"), Some(CharSet::Iso8859_1));
+        assert_eq!(CharSet::from_label("latin1"), Some(CharSet::Iso8859_1));
+        assert_eq!(CharSet::from_label("utf8"), Some(CharSet::Utf8));
+        assert_eq!(CharSet::from_label("x-unknown"), None);
+        assert_eq!(decode_charset(b"\xe9t\xe9", "iso-8859-1"), "été");
+        assert_eq!(decode_charset("ok".as_bytes(), "x-unknown"), "ok");
+    }
+
+    #[test]
+    fn encoded_words() {
+        let gbk = encode(CharSet::Gbk.encode("告警").unwrap());
+        assert_eq!(
+            decode_encoded_words(&format!(
+                "=?GBK?B?{}?= =?utf-8?Q?_ok=3F?=",
+                gbk
+            )),
+            "告警 ok?"
+        );
+        assert_eq!(
+            decode_encoded_words("Re: =?ISO-8859-1?Q?caf=E9?= menu"),
+            "Re: café menu"
+        );
+        assert_eq!(decode_encoded_words("a =?x?Z?y?= b"), "a =?x?Z?y?= b");
+        assert_eq!(decode_encoded_words("plain"), "plain");
+    }
+
+    #[tokio::test]
+    async fn part_in_charset() {
+        let part = Alternative {
+            filename: None,
+            content: String::from("监控告警"),
+            source: None,
+            charset: Some(CharSet::Gb18030),
+            content_type: ContentType::TextPlain.into(),
+            encoding: ContentTransferEncoding::Base64,
+            disposition: None,
+            content_id: None,
+        };
+        assert!(part.header().contains("charset=\"gb18030\""));
+        assert_eq!(
+            part.body().await.unwrap(),
+            CharSet::Gb18030.encode("监控告警").unwrap()
+        );
+        let attach = [part];
+        let streamed = stream_encode(&attach).await.unwrap();
+        let text = String::from_utf8(streamed).unwrap();
+        assert!(text
+            .contains(&encode(CharSet::Gb18030.encode("监控告警").unwrap())));
+    }
+
+    #[tokio::test]
+    async fn generated_headers() {
+        let headers = Headers {
+            host: Some(String::from("jckeep.top")),
+            utc: true,
+            ..Headers::default()
+        };
+        let out = mime_encode(
+            "a@example.com",
+            "b@example.com",
+            "headers",
+            ContentTransferEncoding::Base64,
+            ContentType::TextPlain,
+            "hi",
+            None,
+            Some(&headers),
+        )
+        .await
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().take(6).collect();
+        assert!(lines[0].starts_with("Date: ") && lines[0].ends_with("+0000"));
+        assert!(DateTime::parse_from_rfc2822(&lines[0][6..]).is_ok());
+        assert_eq!(lines[1], "From: a@example.com");
+        assert!(lines[4].starts_with("Message-ID: <"));
+        assert!(lines[4].ends_with("@jckeep.top>"));
+        assert_eq!(lines[5], "MIME-Version: 1.0");
+
+        let a = headers.resolve().unwrap();
+        let b = headers.resolve().unwrap();
+        assert_ne!(a.message_id, b.message_id);
+        let again = a.resolve().unwrap();
+        assert_eq!(again.date, a.date);
+        assert_eq!(again.message_id, a.message_id);
+    }
+
+    #[test]
+    fn overridden_headers() {
+        let (header, _) = message_header(
+            "a@example.com",
+            "b@example.com",
+            "headers",
+            ContentTransferEncoding::Base64,
+            &ContentType::TextPlain.into(),
+            None,
+            &fixed_headers(),
+        )
+        .unwrap();
+        assert!(header.starts_with(
+            "Date: Mon, 19 Oct 2026 08:30:00 +0800\r\nFrom: a@example.com\r\n"
+        ));
+        assert!(header.contains("Message-ID: <1.2@example.com>\r\n"));
+
+        for id in ["1.2@example.com", "<1.2>", "<a b@c>", "<a@b\r\nX: y>"] {
+            let headers = Headers {
+                message_id: Some(String::from(id)),
+                ..Headers::default()
+            };
+            assert!(headers.resolve().is_err(), "{}", id);
+        }
+        let headers = Headers {
+            date: Some(String::from("yesterday")),
+            ..Headers::default()
+        };
+        assert!(headers.resolve().is_err());
+        let headers = Headers {
+            host: Some(String::from("bad host")),
+            ..Headers::default()
+        };
+        assert!(headers.resolve().is_err());
+    }
+
+    #[test]
+    fn required_headers() {
+        let ct = ContentType::TextPlain.into();
+        let headers = Headers::default();
+        let encode = |from, to| {
+            message_header(
+                from,
+                to,
+                "",
+                ContentTransferEncoding::Base64,
+                &ct,
+                None,
+                &headers,
+            )
+        };
+        assert!(encode("", "b@example.com").is_err());
+        assert!(encode("a@example.com", " ").is_err());
+        assert!(encode("a@example.com", "b@example.com").is_ok());
+        assert!(encode("a@example.com", "b@example.com\r\nBcc: c@x").is_err());
+        assert!(encode("G: a@example.com;", "b@example.com").is_err());
+        let (header, _) =
+            encode("Ops <a@例子.测试>", "b@example.com, Team:;").unwrap();
+        assert!(header.contains(
+            "From: Ops <a@xn--fsqu00a.xn--0zwm56d>\r\n\
+             To: b@example.com, Team:;\r\n"
+        ));
+    }
+
+    const INJECTIONS: [&str; 4] = [
+        "x\r\nBcc: victim@example.com",
+        "x\nBcc: victim@example.com",
+        "x\rBcc: victim@example.com",
+        "x\0Bcc: victim@example.com",
+    ];
+
+    fn injected(header: &str) -> bool {
+        header
+            .split(['\r', '\n'])
+            .any(|line| line.starts_with("Bcc:"))
+            || header.contains('\0')
+    }
+
+    #[test]
+    fn header_injection() {
+        let ct: MediaType = ContentType::TextPlain.into();
+        let headers = fixed_headers();
+        let encode = |from: &str, to: &str, subject: &str, ct: &MediaType| {
+            message_header(
+                from,
+                to,
+                subject,
+                ContentTransferEncoding::Base64,
+                ct,
+                None,
+                &headers,
+            )
+        };
+        for bad in INJECTIONS {
+            let (header, _) =
+                encode("a@example.com", "b@example.com", bad, &ct).unwrap();
+            assert!(!injected(&header), "subject {:?}", bad);
+
+            let from = format!("a@example.com{}", bad);
+            assert!(encode(&from, "b@example.com", "", &ct).is_err());
+            let to = format!("Bob <b@example.com>{}", bad);
+            assert!(encode("a@example.com", &to, "", &ct).is_err());
+
+            let with_param = ct.clone().with_param("format", bad);
+            let (header, _) =
+                encode("a@example.com", "b@example.com", "", &with_param)
+                    .unwrap();
+            assert!(!injected(&header), "param {:?}", bad);
+
+            for h in [
+                Headers {
+                    date: Some(format!(
+                        "Mon, 19 Oct 2026 08:30:00 +0800{}",
+                        bad
+                    )),
+                    ..Headers::default()
+                },
+                Headers {
+                    message_id: Some(format!("<1.2@example.com>{}", bad)),
+                    ..Headers::default()
+                },
+                Headers {
+                    host: Some(format!("example.com{}", bad)),
+                    ..Headers::default()
+                },
+            ] {
+                assert!(h.resolve().is_err(), "{:?}", h);
+            }
+
+            let part = Alternative {
+                content_id: Some(format!("cid@example.com{}", bad)),
+                ..Alternative::from_bytes(&format!("a.txt{}", bad), vec![])
+            };
+            assert!(!injected(&part.header()), "part {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn quote_injection() {
+        let evil = "a.txt\"; name=\"evil.exe";
+        let part = Alternative::from_bytes(evil, b"hi".to_vec());
+        let header = part.header();
+        let ct = header.lines().next().unwrap();
+        let ct: MediaType = ct["Content-Type: ".len()..].parse().unwrap();
+        assert_eq!(ct.param("name"), Some(evil));
+        assert_eq!(ct.params().len(), 2);
+        assert!(header.contains(
+            "Content-Disposition: attachment; \
+             filename=\"a.txt\\\"; name=\\\"evil.exe\"\r\n"
+        ));
+
+        let part = Alternative {
+            content_id: Some(String::from("<a>\"@b>")),
+            ..part
+        };
+        assert!(part.header().contains("Content-ID: <a@b>\r\n"));
+
+        let ct = MediaType::new("text/plain; x=\"", "plain")
+            .with_param("a\"=b", "c");
+        assert_eq!(ct.to_string(), "textplainx/plain; ab=\"c\"");
+    }
+}
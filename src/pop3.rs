@@ -1,17 +1,30 @@
 #![allow(unused)]
-use std::time::Duration;
+use std::{fmt, sync::Arc, time::Duration};
 
 use bytes::{Buf, BufMut};
+use md5::{Digest, Md5};
 use tokio::{
-    fs::File,
-    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, WriteHalf},
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite,
+        AsyncWriteExt, BufReader,
+    },
     net::TcpStream,
+    sync::watch,
     time::timeout,
 };
+use tokio_rustls::rustls;
+
+use crate::{
+    auth::{Credentials, Proof, SaslServer, SaslStep},
+    config::MailStore,
+    mailbox::{to_wire, Mailbox},
+    tls::{self, MaybeTls, TlsMode, TlsVerify},
+};
 
 #[derive(Debug, Clone)]
 pub enum Pop3Command {
     LIST,
+    UIDL,
     INFO,
     USER(String),
     RETR(i32),
@@ -20,20 +33,114 @@ pub enum Pop3Command {
     RSET,
     QUIT,
     NOOP,
+    STAT,
+    PASS(String),
+    CAPA,
+    STLS,
+    /// Mechanism and initial response.
+    AUTH(String, Option<String>),
 }
 
+/// Why a POP3 client command failed.
 #[derive(Debug)]
+pub enum Pop3Error {
+    Io(io::Error),
+    /// The server answered `-ERR`, with the text that followed it.
+    Server(String),
+    /// The server's answer does not follow RFC 1939.
+    Protocol(&'static str),
+    /// The client lacks what the command needs, such as a token for
+    /// XOAUTH2.
+    Config(&'static str),
+}
+
+impl fmt::Display for Pop3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pop3Error::Io(e) => write!(f, "{}", e),
+            Pop3Error::Server(text) => write!(f, "-ERR {}", text),
+            Pop3Error::Protocol(what) => write!(f, "protocol error: {}", what),
+            Pop3Error::Config(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl std::error::Error for Pop3Error {}
+
+impl From<io::Error> for Pop3Error {
+    fn from(e: io::Error) -> Self {
+        Pop3Error::Io(e)
+    }
+}
+
+/// Response to STAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub count: usize,
+    /// Total size of the maildrop in octets.
+    pub size: usize,
+}
+
+/// A line of a LIST response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListEntry {
+    pub number: u32,
+    pub size: usize,
+}
+
+/// A line of a UIDL response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UidlEntry {
+    pub number: u32,
+    /// Unique id, stable across sessions.
+    pub uid: String,
+}
+
+/// How the client logs in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pop3Auth {
+    /// The best the server offers for the credentials given: XOAUTH2 with
+    /// a token, then SASL PLAIN, then APOP, then USER/PASS.
+    #[default]
+    Auto,
+    UserPass,
+    /// RFC 1939 APOP, which never sends the password itself.
+    Apop,
+    /// RFC 5034 AUTH with the PLAIN mechanism.
+    Plain,
+    /// AUTH XOAUTH2 with an OAuth 2.0 access token.
+    XOAuth2,
+}
+
 pub struct Pop3Client {
     email: Option<String>,
     password: Option<String>,
+    token: Option<String>,
+    auth: Pop3Auth,
     host: Option<String>,
-    upstream: Option<TcpStream>,
-    buf: Vec<u8>,
-    content_buffer: Vec<u8>,
+    upstream: Option<BufReader<MaybeTls>>,
+    tls: TlsMode,
+    verify: TlsVerify,
+}
+
+/// Leaves out the password and token.
+impl fmt::Debug for Pop3Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pop3Client")
+            .field("email", &self.email)
+            .field("auth", &self.auth)
+            .field("host", &self.host)
+            .field("tls", &self.tls)
+            .field("verify", &self.verify)
+            .field("connected", &self.upstream.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Pop3Client {
-    pub async fn cmd(&mut self, command: Pop3Command) -> Result<String, ()> {
+    /// Sends a command and returns the body of its response, empty for
+    /// commands without one. STAT gives the text of its status line.
+    pub async fn cmd(&mut self, command: Pop3Command) -> Result<Vec<u8>, ()> {
         if self.upstream.is_some() {
             pop3_upstream_poll(self, command).await
         } else {
@@ -49,40 +156,264 @@ impl Pop3Client {
             }
         }
     }
+
+    /// The connection, logging in first if there is none yet.
+    async fn upstream(
+        &mut self,
+    ) -> Result<&mut BufReader<MaybeTls>, Pop3Error> {
+        if self.upstream.is_none() {
+            self.upstream = Some(pop3_upstream_connect(self).await?);
+        }
+        Ok(self.upstream.as_mut().unwrap())
+    }
+
+    /// Sends a command and returns the text after `+OK`.
+    async fn simple(&mut self, command: &str) -> Result<String, Pop3Error> {
+        let c = self.upstream().await?;
+        c.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        read_status(c).await
+    }
+
+    /// Sends a command with a multi-line response and returns its body.
+    async fn multi_line(
+        &mut self,
+        command: &str,
+    ) -> Result<Vec<u8>, Pop3Error> {
+        self.simple(command).await?;
+        read_multi_line(self.upstream.as_mut().unwrap()).await
+    }
+
+    pub async fn stat(&mut self) -> Result<Stat, Pop3Error> {
+        let text = self.simple("STAT").await?;
+        let mut words = text.split_whitespace().map(str::parse);
+        match (words.next(), words.next()) {
+            (Some(Ok(count)), Some(Ok(size))) => Ok(Stat { count, size }),
+            _ => Err(Pop3Error::Protocol("malformed STAT response")),
+        }
+    }
+
+    pub async fn list(&mut self) -> Result<Vec<ListEntry>, Pop3Error> {
+        let body = self.multi_line("LIST").await?;
+        scan_listing(&body, |number, size| {
+            Some(ListEntry {
+                number,
+                size: size.parse().ok()?,
+            })
+        })
+    }
+
+    pub async fn uidl(&mut self) -> Result<Vec<UidlEntry>, Pop3Error> {
+        let body = self.multi_line("UIDL").await?;
+        scan_listing(&body, |number, uid| {
+            Some(UidlEntry {
+                number,
+                uid: uid.to_string(),
+            })
+        })
+    }
+
+    /// The message as sent, with dot-stuffing removed.
+    pub async fn retr(&mut self, number: u32) -> Result<Vec<u8>, Pop3Error> {
+        self.multi_line(&format!("RETR {}", number)).await
+    }
+
+    /// Marks a message deleted. It is removed when the session ends with
+    /// [`Pop3Client::quit`].
+    pub async fn dele(&mut self, number: u32) -> Result<(), Pop3Error> {
+        self.simple(&format!("DELE {}", number)).await.map(|_| ())
+    }
+
+    /// Ends the session, which commits deletions.
+    pub async fn quit(&mut self) -> Result<(), Pop3Error> {
+        if self.upstream.is_none() {
+            return Ok(());
+        }
+        let result = self.simple("QUIT").await.map(|_| ());
+        self.upstream = None;
+        result
+    }
+}
+
+/// Reads a line including its terminator, failing at the end of input.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    line: &mut Vec<u8>,
+) -> Result<(), Pop3Error> {
+    line.clear();
+    if r.read_until(b'\n', line).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// The text after `+OK`, the server's error for `-ERR`, or `None` if the
+/// line is not a status line. Status indicators are case-sensitive.
+fn parse_status(line: &[u8]) -> Option<Result<String, Pop3Error>> {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(['\r', '\n']);
+    let text = |rest: &str| {
+        (rest.is_empty() || rest.starts_with(' '))
+            .then(|| rest.trim_start().to_string())
+    };
+    if let Some(rest) = line.strip_prefix("+OK") {
+        text(rest).map(Ok)
+    } else if let Some(rest) = line.strip_prefix("-ERR") {
+        text(rest).map(|text| Err(Pop3Error::Server(text)))
+    } else {
+        None
+    }
+}
+
+/// Reads a status line, returning the text after `+OK`.
+async fn read_status<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> Result<String, Pop3Error> {
+    let mut line = Vec::new();
+    read_line(r, &mut line).await?;
+    parse_status(&line)
+        .unwrap_or(Err(Pop3Error::Protocol("malformed status line")))
+}
+
+/// Adds a line of a multi-line body with its dot-stuffing removed. Returns
+/// true for the terminating line, which is not added.
+fn push_body_line(body: &mut Vec<u8>, line: &[u8]) -> bool {
+    if line == b".\r\n" || line == b".\n" {
+        return true;
+    }
+    body.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+    false
+}
+
+/// Reads the body of a multi-line response up to the line holding a single
+/// dot. Lines are collected whole, so the terminator is found wherever the
+/// reads happen to end, and bytes are kept as they are.
+async fn read_multi_line<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> Result<Vec<u8>, Pop3Error> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        read_line(r, &mut line).await?;
+        if push_body_line(&mut body, &line) {
+            return Ok(body);
+        }
+    }
+}
+
+/// Reads a multi-line response whose status line may be missing, as our own
+/// server used to send LIST, TOP, RETR and INFO bodies without one.
+async fn read_content<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> Result<Vec<u8>, Pop3Error> {
+    let mut line = Vec::new();
+    read_line(r, &mut line).await?;
+    match parse_status(&line) {
+        Some(Ok(_)) => read_multi_line(r).await,
+        Some(Err(e)) => Err(e),
+        None => {
+            let mut body = Vec::new();
+            if !push_body_line(&mut body, &line) {
+                body.extend(read_multi_line(r).await?);
+            }
+            Ok(body)
+        }
+    }
+}
+
+/// Parses `number value` lines of a LIST or UIDL body.
+fn scan_listing<T>(
+    body: &[u8],
+    entry: impl Fn(u32, &str) -> Option<T>,
+) -> Result<Vec<T>, Pop3Error> {
+    String::from_utf8_lossy(body)
+        .lines()
+        .map(|line| {
+            let mut words = line.split_whitespace();
+            let number = words.next()?.parse().ok()?;
+            entry(number, words.next()?)
+        })
+        .collect::<Option<Vec<T>>>()
+        .ok_or(Pop3Error::Protocol("malformed listing"))
 }
 
 #[derive(Debug)]
 pub struct Pop3UserState {
     pub user: Option<String>,
-    pub file: Option<File>,
-    pub buf: String,
     pub wbuf: Vec<u8>,
-    pub mails: Vec<String>,
-    pub froms: Vec<String>,
-    pub times: Vec<String>,
-    pub subjects: Vec<String>,
+    pub store: MailStore,
+    /// Index of the user's mail, once logged in.
+    pub mailbox: Option<Mailbox>,
+    /// Certificate offered through STLS.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Whether the connection is encrypted, or is about to be after STLS.
+    pub secure: bool,
+    /// Refuse USER and PASS until the connection is encrypted.
+    pub require_tls: bool,
+    /// Checked by PASS and AUTH. Without it, USER alone logs in.
+    pub credentials: Option<Arc<Credentials>>,
+    /// Name given by USER, waiting for PASS.
+    pub login: Option<String>,
+    /// AUTH exchange waiting for the next response.
+    pub sasl: Option<SaslServer>,
+    /// Turns true when the server is stopping. The session then ends
+    /// before reading another command.
+    pub shutdown: Option<watch::Receiver<bool>>,
 }
 
 impl Pop3UserState {
     pub fn new() -> Self {
         Self {
             user: None,
-            file: None,
-            buf: String::new(),
             wbuf: vec![0; 4096],
-            mails: Vec::new(),
-            froms: Vec::new(),
-            times: Vec::new(),
-            subjects: Vec::new(),
+            store: MailStore::default(),
+            mailbox: None,
+            tls: None,
+            secure: false,
+            require_tls: false,
+            credentials: None,
+            login: None,
+            sasl: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_store(store: MailStore) -> Self {
+        Self {
+            store,
+            ..Self::new()
         }
     }
+
+    fn stls_offered(&self) -> bool {
+        self.tls.is_some() && !self.secure
+    }
+
+    fn login_allowed(&self) -> bool {
+        self.secure || !self.require_tls
+    }
 }
 
-#[derive(Debug)]
 pub struct Pop3Builder {
     email: String,
     password: String,
+    token: String,
+    auth: Pop3Auth,
     host: String,
+    tls: TlsMode,
+    verify: TlsVerify,
+}
+
+/// Leaves out the password and token.
+impl fmt::Debug for Pop3Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pop3Builder")
+            .field("email", &self.email)
+            .field("auth", &self.auth)
+            .field("host", &self.host)
+            .field("tls", &self.tls)
+            .field("verify", &self.verify)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Pop3Builder {
@@ -90,7 +421,11 @@ impl Pop3Builder {
         Self {
             email: String::new(),
             password: String::new(),
+            token: String::new(),
+            auth: Pop3Auth::Auto,
             host: String::new(),
+            tls: TlsMode::None,
+            verify: TlsVerify::WebPki,
         }
     }
 
@@ -104,67 +439,93 @@ impl Pop3Builder {
         self
     }
 
+    /// OAuth 2.0 access token for XOAUTH2.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = String::from(token);
+        self
+    }
+
+    pub fn auth(mut self, auth: Pop3Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
     pub fn host(mut self, host: &str) -> Self {
         self.host = String::from(host);
         self
     }
 
+    /// Implicit TLS, usually on port 995, or an STLS upgrade before login.
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// How the server certificate is checked when TLS is used.
+    pub fn verify(mut self, verify: TlsVerify) -> Self {
+        self.verify = verify;
+        self
+    }
+
     pub async fn build(self) -> Pop3Client {
         let pw = if self.password.is_empty() {
             None
         } else {
             Some(self.password)
         };
+        let token = if self.token.is_empty() {
+            None
+        } else {
+            Some(self.token)
+        };
         Pop3Client {
             email: Some(self.email),
             password: pw,
+            token,
+            auth: self.auth,
             host: Some(self.host),
             upstream: None,
-            buf: vec![0; 4096],
-            content_buffer: vec![0; 16 * 4096],
+            tls: self.tls,
+            verify: self.verify,
         }
     }
 }
 
 async fn pop3_upstream_connect(
     pop: &mut Pop3Client,
-) -> Result<TcpStream, &'static str> {
+) -> Result<BufReader<MaybeTls>, Pop3Error> {
     for _ in 0..5 {
-        match timeout(Duration::from_millis(500), async {
-            TcpStream::connect(pop.host.as_ref().unwrap())
-                .await
-                .unwrap()
-        })
+        match timeout(
+            Duration::from_millis(500),
+            TcpStream::connect(pop.host.as_ref().unwrap()),
+        )
         .await
         {
-            Ok(mut c) => {
-                let buf = &mut pop.buf;
-                c.read(buf).await.unwrap();
-                if !buf.starts_with(b"+OK") {
-                    return Err("connection refused");
-                }
-                c.write(
-                    format!("USER {}\r\n", pop.email.as_ref().unwrap())
-                        .as_bytes(),
-                )
-                .await
-                .unwrap();
-                c.read(buf).await.unwrap();
-                if !buf.starts_with(b"+OK") {
-                    return Err("email error");
-                }
-                if pop.password.is_some() {
-                    c.write(
-                        format!("PASS {}\r\n", pop.password.as_ref().unwrap())
-                            .as_bytes(),
-                    )
-                    .await
-                    .unwrap();
-                    c.read(buf).await.unwrap();
-                    if !buf.starts_with(b"+OK") {
-                        return Err("password error");
+            Ok(c) => {
+                let host = pop.host.as_deref().unwrap();
+                let mut c = match pop.tls {
+                    TlsMode::Implicit => {
+                        let config = tls::client_config(&pop.verify)?;
+                        BufReader::new(tls::connect(config, host, c?).await?)
                     }
+                    _ => BufReader::new(MaybeTls::Plain(c?)),
+                };
+                let greeting = read_status(&mut c).await?;
+                if pop.tls == TlsMode::StartTls {
+                    let config = tls::client_config(&pop.verify)?;
+                    c.write_all(b"STLS\r\n").await?;
+                    read_status(&mut c).await?;
+                    // Anything already buffered was sent in plain text and
+                    // could have been injected.
+                    if !c.buffer().is_empty() {
+                        return Err(Pop3Error::Protocol("data after STLS"));
+                    }
+                    let MaybeTls::Plain(tcp) = c.into_inner() else {
+                        unreachable!()
+                    };
+                    c = BufReader::new(tls::connect(config, host, tcp).await?);
                 }
+                login(pop, &mut c, &greeting).await?;
                 return Ok(c);
             }
             Err(_) => {
@@ -173,21 +534,169 @@ async fn pop3_upstream_connect(
         }
     }
     eprintln!("timeout 5 times");
-    Err("5 times timeout")
+    Err(io::Error::new(io::ErrorKind::TimedOut, "5 times timeout").into())
+}
+
+/// Capabilities listed by CAPA, none if the server does not know it.
+async fn capabilities(
+    c: &mut BufReader<MaybeTls>,
+) -> Result<Vec<String>, Pop3Error> {
+    c.write_all(b"CAPA\r\n").await?;
+    match read_status(c).await {
+        Ok(_) => {}
+        Err(Pop3Error::Server(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    }
+    let body = read_multi_line(c).await?;
+    Ok(String::from_utf8_lossy(&body)
+        .lines()
+        .map(|line| line.trim().to_string())
+        .collect())
+}
+
+/// Mechanisms of the `SASL` capability, in upper case.
+fn sasl_mechanisms(capabilities: &[String]) -> Vec<String> {
+    capabilities
+        .iter()
+        .filter_map(|c| c.strip_prefix("SASL "))
+        .flat_map(str::split_whitespace)
+        .map(str::to_ascii_uppercase)
+        .collect()
+}
+
+/// The `<...@...>` timestamp of a greeting offering APOP.
+fn apop_timestamp(greeting: &str) -> Option<&str> {
+    let start = greeting.find('<')?;
+    let end = start + greeting[start..].find('>')?;
+    let timestamp = &greeting[start..=end];
+    timestamp.contains('@').then_some(timestamp)
+}
+
+fn apop_digest(timestamp: &str, secret: &str) -> String {
+    let digest = Md5::digest(format!("{}{}", timestamp, secret));
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A command line with credentials in it, which must not smuggle in
+/// another command.
+fn credential_line(line: String) -> Result<String, Pop3Error> {
+    if line.contains(|c: char| c.is_control()) {
+        return Err(Pop3Error::Config("control characters in credentials"));
+    }
+    Ok(line + "\r\n")
+}
+
+/// Runs an AUTH exchange sending `response` to the server's challenge.
+async fn sasl(
+    c: &mut BufReader<MaybeTls>,
+    mechanism: &str,
+    response: &str,
+) -> Result<(), Pop3Error> {
+    let is_challenge =
+        |line: &[u8]| line.starts_with(b"+ ") || line.trim_ascii_end() == b"+";
+    c.write_all(format!("AUTH {}\r\n", mechanism).as_bytes())
+        .await?;
+    let mut line = Vec::new();
+    read_line(c, &mut line).await?;
+    if !is_challenge(&line) {
+        return Err(match parse_status(&line) {
+            Some(Err(e)) => e,
+            _ => Pop3Error::Protocol("expected a SASL challenge"),
+        });
+    }
+    c.write_all(format!("{}\r\n", base64::encode(response)).as_bytes())
+        .await?;
+    read_line(c, &mut line).await?;
+    // XOAUTH2 reports a failure as a challenge that must be answered with
+    // an empty line before the -ERR.
+    if is_challenge(&line) {
+        c.write_all(b"\r\n").await?;
+        read_line(c, &mut line).await?;
+    }
+    parse_status(&line)
+        .unwrap_or(Err(Pop3Error::Protocol("malformed status line")))
+        .map(|_| ())
+}
+
+async fn login(
+    pop: &Pop3Client,
+    c: &mut BufReader<MaybeTls>,
+    greeting: &str,
+) -> Result<(), Pop3Error> {
+    let user = pop.email.as_deref().unwrap_or_default();
+    let password = pop.password.as_deref();
+    let token = pop.token.as_deref();
+    // Without credentials there is nothing to choose, and some servers,
+    // our own included, do not know CAPA.
+    let has_credentials = password.is_some() || token.is_some();
+    let mechanisms = match pop.auth {
+        Pop3Auth::Auto if has_credentials => {
+            sasl_mechanisms(&capabilities(c).await?)
+        }
+        _ => Vec::new(),
+    };
+    let offered = |m: &str| mechanisms.iter().any(|o| o == m);
+    let auth = match pop.auth {
+        Pop3Auth::Auto if token.is_some() && offered("XOAUTH2") => {
+            Pop3Auth::XOAuth2
+        }
+        Pop3Auth::Auto if password.is_some() && offered("PLAIN") => {
+            Pop3Auth::Plain
+        }
+        Pop3Auth::Auto
+            if password.is_some() && apop_timestamp(greeting).is_some() =>
+        {
+            Pop3Auth::Apop
+        }
+        Pop3Auth::Auto => Pop3Auth::UserPass,
+        auth => auth,
+    };
+
+    match auth {
+        Pop3Auth::XOAuth2 => {
+            let token = token.ok_or(Pop3Error::Config("no OAuth token"))?;
+            let response =
+                format!("user={}\x01auth=Bearer {}\x01\x01", user, token);
+            sasl(c, "XOAUTH2", &response).await
+        }
+        Pop3Auth::Plain => {
+            let password = password.ok_or(Pop3Error::Config("no password"))?;
+            sasl(c, "PLAIN", &format!("\0{}\0{}", user, password)).await
+        }
+        Pop3Auth::Apop => {
+            let password = password.ok_or(Pop3Error::Config("no password"))?;
+            let timestamp = apop_timestamp(greeting)
+                .ok_or(Pop3Error::Protocol("greeting has no APOP timestamp"))?;
+            let digest = apop_digest(timestamp, password);
+            let line = credential_line(format!("APOP {} {}", user, digest))?;
+            c.write_all(line.as_bytes()).await?;
+            read_status(c).await.map(|_| ())
+        }
+        Pop3Auth::UserPass | Pop3Auth::Auto => {
+            let line = credential_line(format!("USER {}", user))?;
+            c.write_all(line.as_bytes()).await?;
+            read_status(c).await?;
+            if let Some(password) = password {
+                let line = credential_line(format!("PASS {}", password))?;
+                c.write_all(line.as_bytes()).await?;
+                read_status(c).await?;
+            }
+            Ok(())
+        }
+    }
 }
 
 async fn pop3_upstream_poll(
     pop: &mut Pop3Client,
     cmd: Pop3Command,
-) -> Result<String, ()> {
-    let mut buf = vec![0; 1024];
+) -> Result<Vec<u8>, ()> {
     let mut c = pop.upstream.as_mut().unwrap();
     c.write(b"NOOP\r\n").await.unwrap();
-    match c.read(&mut pop.buf).await {
-        Ok(n) if n > 0 => {}
-        _ => {
-            pop3_upstream_connect(pop).await.unwrap();
-            c = pop.upstream.as_mut().unwrap();
+    match read_status(c).await {
+        Ok(_) => {}
+        Err(_) => {
+            pop.upstream = pop3_upstream_connect(pop).await.ok();
+            c = pop.upstream.as_mut().ok_or(())?;
         }
     }
 
@@ -195,6 +704,9 @@ async fn pop3_upstream_poll(
         Pop3Command::LIST => {
             c.write(b"LIST\r\n").await.unwrap();
         }
+        Pop3Command::UIDL => {
+            c.write_all(b"UIDL\r\n").await.unwrap();
+        }
         Pop3Command::INFO => {
             c.write(b"INFO\r\n").await.unwrap();
         }
@@ -212,106 +724,211 @@ async fn pop3_upstream_poll(
             c.write(format!("DELE {}\r\n", msg).as_bytes())
                 .await
                 .unwrap();
-            pop3_upstream_readline(pop).await?;
-            if pop.buf.starts_with(b"+Ok") {
-                return Ok(String::new());
-            } else {
-                return Err(());
-            }
+            return match read_status(c).await {
+                Ok(_) => Ok(Vec::new()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(())
+                }
+            };
         }
         Pop3Command::QUIT => {
             c.write(b"QUIT\r\n").await.unwrap();
             pop.upstream = None;
-            return Ok(String::new());
+            return Ok(Vec::new());
         }
         Pop3Command::RSET => {
             c.write(b"RSET\r\n").await.unwrap();
-            pop3_upstream_readline(pop).await?;
-            return Ok(String::new());
+            read_status(c).await.map_err(|e| eprintln!("{}", e))?;
+            return Ok(Vec::new());
         }
         Pop3Command::NOOP => {
             c.write(b"NOOP\r\n").await.unwrap();
-            pop3_upstream_readline(pop).await?;
-            if pop.buf.starts_with(b"+Ok") {
-                return Ok(String::new());
-            } else {
-                return Err(());
-            }
+            return match read_status(c).await {
+                Ok(_) => Ok(Vec::new()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(())
+                }
+            };
         }
         Pop3Command::USER(u) => {
             c.write(format!("USER {}\r\n", u).as_bytes()).await.unwrap();
-            pop3_upstream_readline(pop).await?;
-            if pop.buf.starts_with(b"+Ok") {
-                return Ok(String::new());
-            } else {
-                return Err(());
-            }
+            return match read_status(c).await {
+                Ok(_) => Ok(Vec::new()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(())
+                }
+            };
         }
-        _ => {
-            unimplemented!()
+        Pop3Command::STAT => {
+            c.write_all(b"STAT\r\n").await.unwrap();
+            return match read_status(c).await {
+                Ok(text) => Ok(text.into_bytes()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(())
+                }
+            };
+        }
+        Pop3Command::CAPA => {
+            c.write_all(b"CAPA\r\n").await.unwrap();
+            let body = match read_status(c).await {
+                Ok(_) => read_multi_line(c).await,
+                Err(e) => Err(e),
+            };
+            return body.map_err(|e| eprintln!("{}", e));
+        }
+        Pop3Command::PASS(password) => {
+            let line = credential_line(format!("PASS {}", password))
+                .map_err(|e| eprintln!("{}", e))?;
+            c.write_all(line.as_bytes()).await.unwrap();
+            return match read_status(c).await {
+                Ok(_) => Ok(Vec::new()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(())
+                }
+            };
+        }
+        // These change the session in ways only login can follow.
+        Pop3Command::STLS | Pop3Command::AUTH(..) => {
+            eprintln!("use Pop3Builder::tls or Pop3Builder::auth instead");
+            return Err(());
         }
     }
-    pop3_upstream_read_content(pop, cmd).await
+    read_content(c).await.map_err(|e| eprintln!("{}", e))
 }
 
-async fn pop3_upstream_read_content(
-    pop: &mut Pop3Client,
-    cmd: Pop3Command,
-) -> Result<String, ()> {
-    let connect = pop.upstream.as_mut().unwrap();
-    let buf = &mut pop.buf;
-    let mut st = String::new();
-
-    loop {
-        match connect.read(buf).await {
-            Ok(n) if n == 0 => {
-                break Ok(st);
-            }
-            Ok(n) => {
-                let s = String::from_utf8_lossy(&buf[..n]);
-                st.push_str(&format!("{}", s));
-                if s.ends_with(".\r\n") {
-                    break Ok(st);
-                }
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-                break Err(());
-            }
+/// Opens the mailbox of `user`, who has logged in.
+async fn open_mailbox(
+    state: &mut Pop3UserState,
+    user: String,
+) -> Result<(), ()> {
+    match Mailbox::open(&state.store, &user).await {
+        Ok(mailbox) => {
+            state.user = Some(user);
+            state.mailbox = Some(mailbox);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}: {}", state.store.path_for(&user).display(), e);
+            Err(())
         }
     }
 }
 
-async fn pop3_upstream_readline(pop: &mut Pop3Client) -> Result<(), ()> {
-    pop.upstream
-        .as_mut()
-        .unwrap()
-        .read(&mut pop.buf)
+/// Checks `proof` on a blocking thread, since bcrypt is slow on purpose.
+async fn check_proof(
+    credentials: &Arc<Credentials>,
+    user: &str,
+    proof: Proof,
+) -> bool {
+    let (credentials, user) = (credentials.clone(), user.to_string());
+    tokio::task::spawn_blocking(move || credentials.check(&user, &proof))
         .await
-        .unwrap();
+        .unwrap_or(false)
+}
+
+async fn pop3_sasl_step(
+    w: &mut (impl AsyncWrite + Unpin),
+    state: &mut Pop3UserState,
+    step: SaslStep,
+) -> Result<(), ()> {
+    match step {
+        SaslStep::Challenge(next, challenge) => {
+            let line = format!("+ {}\r\n", base64::encode(challenge));
+            w.write_all(line.as_bytes()).await.unwrap();
+            state.sasl = Some(next);
+        }
+        SaslStep::Done(user, proof) => {
+            let Some(credentials) = &state.credentials else {
+                return Err(());
+            };
+            if !check_proof(credentials, &user, proof).await {
+                w.write_all(b"-ERR authentication failed\r\n")
+                    .await
+                    .unwrap();
+                return Ok(());
+            }
+            open_mailbox(state, user).await?;
+            w.write_all(b"+OK\r\n").await.unwrap();
+        }
+        SaslStep::Failed(e) => {
+            w.write_all(format!("-ERR {}\r\n", e).as_bytes())
+                .await
+                .unwrap();
+        }
+    }
     Ok(())
 }
 
+/// Index of message number `msg`, which counts from 1 (RFC 1939).
+fn message_index(msg: i32) -> usize {
+    usize::try_from(msg)
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .unwrap_or(usize::MAX)
+}
+
+/// Sends message `i`, or its first `body_lines` lines, read from disk.
+/// Returns whether it was found.
+async fn send_message(
+    w: &mut (impl AsyncWrite + Unpin),
+    mailbox: &Mailbox,
+    i: usize,
+    body_lines: Option<usize>,
+) -> bool {
+    match mailbox.read(i, body_lines).await {
+        Ok(data) => {
+            let wire = to_wire(&data);
+            let status = format!("+OK {} octets\r\n", wire.len());
+            w.write_all(status.as_bytes()).await.unwrap();
+            w.write_all(&wire).await.unwrap();
+            w.write_all(b".\r\n").await.unwrap();
+            true
+        }
+        Err(e) => {
+            let reply = format!("-ERR {}\r\n", e);
+            w.write_all(reply.as_bytes()).await.unwrap();
+            false
+        }
+    }
+}
+
 pub async fn pop3_handler_state(
-    w: &mut WriteHalf<TcpStream>,
+    w: &mut (impl AsyncWrite + Unpin),
     state: &mut Pop3UserState,
     buf: &[u8],
     n: usize,
 ) -> Result<(), ()> {
-    match pop3_parse_command(buf, n).unwrap() {
+    // During AUTH, lines are responses rather than commands.
+    if let Some(sasl) = state.sasl.take() {
+        let response = String::from_utf8_lossy(&buf[..n]);
+        return pop3_sasl_step(w, state, sasl.step(&response)).await;
+    }
+    let Ok(command) = pop3_parse_command(buf, n) else {
+        w.write_all(b"-ERR malformed command\r\n").await.unwrap();
+        return Ok(());
+    };
+    match command {
         Pop3Command::INFO => {
-            if state.user.is_none() {
+            let Some(mailbox) = &state.mailbox else {
                 return Err(());
-            }
-            let mut info_buf = String::new();
+            };
+            let mut info_buf = String::from("+OK\r\n");
             info_buf.push_str(&format!(
                 "No   {:<20}  {:<30}  {:<15}\r\n",
                 "From", "Time", "Subject"
             ));
-            for i in 0..state.mails.len() {
+            for (i, mail) in mailbox.entries().iter().enumerate() {
                 info_buf.push_str(&format!(
                     "{:<4} {:<20}  {:<30}  {:<15}\r\n",
-                    i, state.froms[i], state.times[i], state.subjects[i]
+                    i + 1,
+                    mail.from,
+                    mail.date,
+                    mail.subject
                 ));
             }
             info_buf.push_str(".\r\n");
@@ -319,34 +936,63 @@ pub async fn pop3_handler_state(
             Ok(())
         }
         Pop3Command::LIST => {
-            if state.user.is_none() {
+            let Some(mailbox) = &state.mailbox else {
                 return Err(());
-            }
-            let mut index = 0;
-            let mut tmp_buf = String::new();
-            for mail in &state.mails {
-                tmp_buf
-                    .push_str(format!("{} {}\r\n", index, mail.len()).as_str());
-                index += 1;
+            };
+            let entries = mailbox.entries();
+            let size: u64 = entries.iter().map(|mail| mail.size).sum();
+            let mut tmp_buf =
+                format!("+OK {} messages ({} octets)\r\n", entries.len(), size);
+            for (index, mail) in entries.iter().enumerate() {
+                tmp_buf.push_str(&format!("{} {}\r\n", index + 1, mail.size));
             }
             tmp_buf.push_str(".\r\n");
             w.write_all(tmp_buf.as_bytes()).await.unwrap();
             Ok(())
         }
-        Pop3Command::RETR(msg) => {
-            if state.user.is_none() {
+        Pop3Command::UIDL => {
+            let Some(mailbox) = &state.mailbox else {
                 return Err(());
+            };
+            let mut reply = String::from("+OK\r\n");
+            for (index, mail) in mailbox.entries().iter().enumerate() {
+                reply.push_str(&format!("{} {}\r\n", index + 1, mail.uid));
             }
-            let tmp_buf = format!("{}\r\n.\r\n", state.mails[msg as usize]);
-            w.write_all(tmp_buf.as_bytes()).await.unwrap();
+            reply.push_str(".\r\n");
+            w.write_all(reply.as_bytes()).await.unwrap();
             Ok(())
         }
-        Pop3Command::TOP(msg, _) => {
-            if state.user.is_none() {
+        Pop3Command::STAT => {
+            let Some(mailbox) = &state.mailbox else {
+                return Err(());
+            };
+            let entries = mailbox.entries();
+            let size: u64 = entries.iter().map(|mail| mail.size).sum();
+            let reply = format!("+OK {} {}\r\n", entries.len(), size);
+            w.write_all(reply.as_bytes()).await.unwrap();
+            Ok(())
+        }
+        Pop3Command::RETR(msg) => {
+            let Some(mailbox) = &mut state.mailbox else {
                 return Err(());
+            };
+            let i = message_index(msg);
+            if !send_message(w, mailbox, i, None).await {
+                return Ok(());
             }
-            let tmp_buf = format!("{}\r\n.\r\n", state.mails[msg as usize]);
-            w.write_all(tmp_buf.as_bytes()).await.unwrap();
+            // A retrieved Maildir message is no longer new.
+            if let Err(e) = mailbox.mark_seen(i).await {
+                eprintln!("{}", e);
+            }
+            Ok(())
+        }
+        Pop3Command::TOP(msg, n) => {
+            let Some(mailbox) = &state.mailbox else {
+                return Err(());
+            };
+            let i = message_index(msg);
+            let n = usize::try_from(n).unwrap_or(0);
+            send_message(w, mailbox, i, Some(n)).await;
             Ok(())
         }
         Pop3Command::DELE(_) => {
@@ -371,69 +1017,218 @@ pub async fn pop3_handler_state(
             w.write(b"+OK\r\n").await.unwrap();
             Ok(())
         }
-        Pop3Command::USER(u) => {
-            state.user = Some(u);
-            state.file = match File::open(format!(
-                "/var/mail/{}",
-                state.user.as_ref().unwrap()
-            ))
-            .await
-            {
-                Ok(f) => Some(f),
-                Err(_) => return Err(()),
-            };
-            state
-                .file
-                .as_mut()
-                .unwrap()
-                .read_to_string(&mut state.buf)
-                .await
-                .unwrap();
-            for s in state.buf.split("From ") {
-                let mut start = 0;
-                let end = s.len();
-                for i in s.as_bytes() {
-                    start += 1;
-                    if *i == '\n' as u8 {
-                        break;
-                    }
-                }
-                if start == 0 {
-                    continue;
-                }
-                let tmp_str = s[..start].to_string();
-                let mut p = 0;
-                for i in tmp_str.as_bytes() {
-                    p += 1;
-                    if *i == ' ' as u8 {
-                        break;
-                    }
-                }
-                let mail = s[start..end].to_string();
-                if !mail.is_empty() {
-                    if let Some(pt) = mail.as_str().find("Subject: ") {
-                        let tmp_s = mail.as_bytes();
-                        let mut ppt = pt + 9;
-                        for i in pt + 9..tmp_s.len() {
-                            ppt += 1;
-                            if tmp_s[i] == '\n' as u8 {
-                                break;
-                            }
-                        }
-                        state.subjects.push(mail[pt + 9..ppt - 1].to_string());
-                    }
-                    state.mails.push(mail);
-                    state.froms.push(tmp_str[..p - 1].to_string());
-                    state.times.push(tmp_str[p + 1..start - 1].to_string());
+        Pop3Command::CAPA => {
+            let mut capa = String::from("+OK\r\n");
+            if state.login_allowed() {
+                capa.push_str("USER\r\n");
+                if let Some(credentials) = &state.credentials {
+                    let mechanisms = credentials.mechanisms().join(" ");
+                    capa.push_str(&format!("SASL {}\r\n", mechanisms));
                 }
             }
+            if state.stls_offered() {
+                capa.push_str("STLS\r\n");
+            }
+            capa.push_str(".\r\n");
+            w.write_all(capa.as_bytes()).await.unwrap();
+            Ok(())
+        }
+        Pop3Command::STLS => {
+            if !state.stls_offered() || state.user.is_some() {
+                w.write_all(b"-ERR STLS not available\r\n").await.unwrap();
+                return Ok(());
+            }
+            // The caller performs the handshake, see `pop3_start_tls`.
+            w.write_all(b"+OK begin TLS\r\n").await.unwrap();
+            state.secure = true;
+            Ok(())
+        }
+        Pop3Command::PASS(_) | Pop3Command::USER(_) | Pop3Command::AUTH(..)
+            if !state.login_allowed() =>
+        {
+            w.write_all(b"-ERR STLS first\r\n").await.unwrap();
+            Ok(())
+        }
+        Pop3Command::PASS(password) => {
+            let Some(credentials) = &state.credentials else {
+                // USER has logged in already.
+                let reply: &[u8] = match state.user {
+                    Some(_) => b"+OK\r\n",
+                    None => b"-ERR USER first\r\n",
+                };
+                w.write_all(reply).await.unwrap();
+                return Ok(());
+            };
+            let Some(user) = state.login.take() else {
+                w.write_all(b"-ERR USER first\r\n").await.unwrap();
+                return Ok(());
+            };
+            let proof = Proof::Password(password);
+            if !check_proof(credentials, &user, proof).await {
+                w.write_all(b"-ERR authentication failed\r\n")
+                    .await
+                    .unwrap();
+                return Ok(());
+            }
+            open_mailbox(state, user).await?;
+            w.write_all(b"+OK\r\n").await.unwrap();
+            Ok(())
+        }
+        Pop3Command::AUTH(mechanism, initial) => {
+            let offered = state.credentials.as_ref().is_some_and(|c| {
+                c.mechanisms()
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(&mechanism))
+            });
+            if !offered || state.user.is_some() {
+                w.write_all(b"-ERR unsupported mechanism\r\n")
+                    .await
+                    .unwrap();
+                return Ok(());
+            }
+            let step = SaslServer::start(&mechanism, initial.as_deref());
+            pop3_sasl_step(w, state, step).await
+        }
+        Pop3Command::USER(u) if state.credentials.is_some() => {
+            state.login = Some(u);
+            w.write_all(b"+OK\r\n").await.unwrap();
+            Ok(())
+        }
+        Pop3Command::USER(u) => {
+            open_mailbox(state, u).await?;
             w.write(b"+OK\r\n").await.unwrap();
             Ok(())
         }
     }
 }
 
+/// Serves a connection until QUIT, or until it has been idle for `idle`.
+pub async fn pop3_handler(
+    mut stream: MaybeTls,
+    mut state: Pop3UserState,
+    idle: Duration,
+) {
+    if stream.write_all(b"+OK\r\n").await.is_err() {
+        return;
+    }
+    let mut buf = vec![0; 1024];
+    let mut shutdown = state.shutdown.take();
+    loop {
+        let read = tokio::select! {
+            read = timeout(idle, stream.read(&mut buf)) => read,
+            _ = stopping(&mut shutdown) => {
+                stream.write_all(b"-ERR server shutting down\r\n").await.ok();
+                break;
+            }
+        };
+        let n = match read {
+            Ok(Ok(0)) => {
+                println!("connection closed");
+                break;
+            }
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
+                eprintln!("{}", e);
+                break;
+            }
+            Err(_) => {
+                stream.write_all(b"-ERR idle timeout\r\n").await.ok();
+                break;
+            }
+        };
+        if pop3_handler_state(&mut stream, &mut state, &buf, n)
+            .await
+            .is_err()
+        {
+            break;
+        }
+        stream = match pop3_start_tls(stream, &state, idle).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+    }
+    // Flushes what is left and closes TLS properly.
+    stream.shutdown().await.ok();
+}
+
+/// Resolves once `shutdown` turns true. Without a receiver, or once its
+/// sender is gone, it never does.
+async fn stopping(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Completes the handshake after STLS was accepted, giving the client
+/// `idle` to do its part. Anything else is passed through.
+async fn pop3_start_tls(
+    stream: MaybeTls,
+    state: &Pop3UserState,
+    idle: Duration,
+) -> io::Result<MaybeTls> {
+    match (stream, &state.tls) {
+        (MaybeTls::Plain(tcp), Some(config)) if state.secure => {
+            timeout(idle, tls::accept(config.clone(), tcp))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "TLS handshake timed out",
+                    ))
+                })
+        }
+        (stream, _) => Ok(stream),
+    }
+}
+
 fn pop3_parse_command(buf: &[u8], n: usize) -> Result<Pop3Command, ()> {
+    let args = String::from_utf8_lossy(&buf[4.min(n)..n]);
+    let args = args.trim_end_matches(['\r', '\n']);
+    if buf.starts_with(b"USER") {
+        // The name ends up in a mailbox path, so it must not leave the
+        // directory.
+        let user = args.trim();
+        if user.is_empty()
+            || user == ".."
+            || user.contains('/')
+            || user.contains(char::is_control)
+        {
+            return Err(());
+        }
+        return Ok(Pop3Command::USER(user.to_string()));
+    }
+    if buf.starts_with(b"PASS") {
+        let pass = args.strip_prefix(' ').unwrap_or(args);
+        return Ok(Pop3Command::PASS(pass.to_string()));
+    }
+    if buf.starts_with(b"AUTH") {
+        let mut args = args.split_whitespace().map(String::from);
+        let mechanism = args.next().unwrap_or_default();
+        return Ok(Pop3Command::AUTH(mechanism, args.next()));
+    }
+    if buf.starts_with(b"CAPA") {
+        return Ok(Pop3Command::CAPA);
+    }
+    if buf.starts_with(b"STLS") {
+        return Ok(Pop3Command::STLS);
+    }
+    if buf.starts_with(b"STAT") {
+        return Ok(Pop3Command::STAT);
+    }
+    if buf.starts_with(b"UIDL") {
+        return Ok(Pop3Command::UIDL);
+    }
     if buf.starts_with(b"LIST") {
         return Ok(Pop3Command::LIST);
     } else if buf.starts_with(b"RETR") {
@@ -485,17 +1280,6 @@ fn pop3_parse_command(buf: &[u8], n: usize) -> Result<Pop3Command, ()> {
         return Ok(Pop3Command::NOOP);
     } else if buf.starts_with(b"INFO") {
         return Ok(Pop3Command::INFO);
-    } else if buf.starts_with(b"USER") {
-        let mut s = Vec::new();
-        for i in 5..n {
-            let tmp = buf[i];
-            if (tmp > 'a' as u8 && tmp < 'z' as u8)
-                || (tmp > 'A' as u8 && tmp < 'Z' as u8)
-            {
-                s.put_u8(tmp);
-            }
-        }
-        return Ok(Pop3Command::USER(String::from_utf8(s).unwrap()));
     } else {
         return Ok(Pop3Command::QUIT);
     }
@@ -504,6 +1288,551 @@ fn pop3_parse_command(buf: &[u8], n: usize) -> Result<Pop3Command, ()> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::maildir::Maildir;
+    use hmac::{Hmac, Mac};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpListener,
+    };
+    use tokio_rustls::{
+        rustls::{crypto::ring, pki_types::PrivateKeyDer, ServerConfig},
+        TlsAcceptor,
+    };
+
+    /// Answers a logged-in session until QUIT.
+    async fn answer(s: impl AsyncRead + AsyncWrite + Unpin) {
+        let mut s = BufReader::new(s);
+        let mut line = String::new();
+        while s.read_line(&mut line).await.unwrap_or(0) > 0 {
+            let reply: &[u8] = match line.trim_end() {
+                "STAT" => b"+OK 1 5\r\n",
+                "CAPA" => b"+OK\r\nUSER\r\nSTLS\r\n.\r\n",
+                _ => b"+OK\r\n",
+            };
+            s.write_all(reply).await.unwrap();
+            if line.starts_with("QUIT") {
+                break;
+            }
+            line.clear();
+        }
+    }
+
+    /// A TLS stand-in with a self-signed certificate for localhost. Returns
+    /// its address and the certificate in PEM.
+    async fn tls_server(mode: TlsMode, inject: bool) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap();
+        let key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+        let config = ServerConfig::builder_with_provider(Arc::new(
+            ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key)
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if mode == TlsMode::StartTls {
+                        tcp.write_all(b"+OK ready\r\n").await.unwrap();
+                        let mut line = [0; 6];
+                        tcp.read_exact(&mut line).await.unwrap();
+                        assert_eq!(&line, b"STLS\r\n");
+                        let reply: &[u8] = match inject {
+                            true => b"+OK\r\n+OK injected\r\n",
+                            false => b"+OK begin TLS\r\n",
+                        };
+                        tcp.write_all(reply).await.unwrap();
+                    }
+                    if let Ok(mut tls) = acceptor.accept(tcp).await {
+                        if mode == TlsMode::Implicit {
+                            tls.write_all(b"+OK ready\r\n").await.unwrap();
+                        }
+                        answer(tls).await;
+                    }
+                });
+            }
+        });
+        (format!("localhost:{}", port), cert.cert.pem())
+    }
+
+    async fn tls_client(
+        host: &str,
+        mode: TlsMode,
+        verify: TlsVerify,
+    ) -> Pop3Client {
+        Pop3Builder::new()
+            .email("test")
+            .password("secret")
+            .host(host)
+            .tls(mode)
+            .verify(verify)
+            .build()
+            .await
+    }
+
+    /// A server that offers `capa` and accepts only the right credentials
+    /// for user `test`. Returns its address and the commands it received.
+    async fn auth_server(
+        greeting: &'static str,
+        capa: &'static str,
+    ) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = log.clone();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut s = BufReader::new(tcp);
+            s.write_all(greeting.as_bytes()).await.unwrap();
+            let plain = base64::encode("\0test\0secret");
+            let xoauth2 =
+                base64::encode("user=test\x01auth=Bearer tok\x01\x01");
+            let apop = apop_digest("<1.2@example.com>", "secret");
+            let mut line = String::new();
+            while s.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                log.lock().unwrap().push(command.clone());
+                let reply = match command.as_str() {
+                    "CAPA" => format!("+OK\r\n{}.\r\n", capa),
+                    "AUTH PLAIN" | "AUTH XOAUTH2" => String::from("+ \r\n"),
+                    c if c == plain || c == xoauth2 => String::from("+OK\r\n"),
+                    c if c == format!("APOP test {}", apop) => {
+                        String::from("+OK\r\n")
+                    }
+                    "USER test" | "PASS secret" | "STAT" | "QUIT" => {
+                        String::from("+OK 0 0\r\n")
+                    }
+                    _ => String::from("-ERR denied\r\n"),
+                };
+                s.write_all(reply.as_bytes()).await.unwrap();
+                line.clear();
+            }
+        });
+        (host, seen)
+    }
+
+    #[tokio::test]
+    async fn login_mechanisms() {
+        let cases = [
+            ("+OK\r\n", "SASL PLAIN XOAUTH2\r\n", "", "AUTH PLAIN"),
+            ("+OK\r\n", "SASL PLAIN XOAUTH2\r\n", "tok", "AUTH XOAUTH2"),
+            ("+OK <1.2@example.com>\r\n", "USER\r\n", "", "APOP"),
+            ("+OK\r\n", "USER\r\n", "", "USER test"),
+        ];
+        for (greeting, capa, token, expected) in cases {
+            let (host, log) = auth_server(greeting, capa).await;
+            let mut pop = Pop3Builder::new()
+                .email("test")
+                .password("secret")
+                .token(token)
+                .host(&host)
+                .build()
+                .await;
+            assert_eq!(pop.stat().await.unwrap(), Stat { count: 0, size: 0 });
+            let log = log.lock().unwrap();
+            assert_eq!(log[0], "CAPA");
+            assert!(log[1].starts_with(expected), "{:?}", log);
+            // Only PASS sends the password as it is.
+            let sent = log.iter().any(|c| c.contains("secret"));
+            assert_eq!(sent, expected == "USER test");
+        }
+
+        let (host, _) = auth_server("+OK\r\n", "SASL PLAIN\r\n").await;
+        let mut pop = Pop3Builder::new()
+            .email("test")
+            .password("wrong")
+            .host(&host)
+            .build()
+            .await;
+        assert!(matches!(
+            pop.stat().await,
+            Err(Pop3Error::Server(text)) if text == "denied"
+        ));
+    }
+
+    #[test]
+    fn apop_and_debug() {
+        // The example from RFC 1939.
+        assert_eq!(
+            apop_digest("<1896.697170952@dbc.mtview.ca.us>", "tanstaaf"),
+            "c4c9334bac560ecc979e58001b3e22fb"
+        );
+        assert_eq!(
+            apop_timestamp("POP3 server ready <1896.697170952@dbc>"),
+            Some("<1896.697170952@dbc>")
+        );
+        assert_eq!(apop_timestamp("ready <no timestamp>"), None);
+
+        let builder = Pop3Builder::new().password("hunter2").token("ya29.tok");
+        assert!(!format!("{:?}", builder).contains("hunter2"));
+        let pop = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(builder.build());
+        let debug = format!("{:?}", pop);
+        assert!(!debug.contains("hunter2") && !debug.contains("ya29"));
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_and_stls() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        for mode in [TlsMode::Implicit, TlsMode::StartTls] {
+            let (host, pem) = tls_server(mode, false).await;
+            std::fs::write(&ca, pem).unwrap();
+            let mut pop =
+                tls_client(&host, mode, TlsVerify::CaFile(ca.clone())).await;
+            assert_eq!(pop.stat().await.unwrap(), Stat { count: 1, size: 5 });
+            pop.quit().await.unwrap();
+
+            let mut pop = tls_client(&host, mode, TlsVerify::Insecure).await;
+            assert!(pop.stat().await.is_ok());
+
+            // The self-signed certificate is not trusted by default.
+            let mut pop = tls_client(&host, mode, TlsVerify::WebPki).await;
+            assert!(matches!(pop.stat().await, Err(Pop3Error::Io(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn stls_rejects_injected_data() {
+        let (host, _) = tls_server(TlsMode::StartTls, true).await;
+        let mut pop =
+            tls_client(&host, TlsMode::StartTls, TlsVerify::Insecure).await;
+        assert!(matches!(pop.stat().await, Err(Pop3Error::Protocol(_))));
+    }
+    #[tokio::test]
+    async fn server_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap();
+        std::fs::write(dir.path().join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(
+            dir.path().join("key.pem"),
+            cert.key_pair.serialize_pem(),
+        )
+        .unwrap();
+        let config = tls::server_config(
+            dir.path().join("cert.pem"),
+            dir.path().join("key.pem"),
+        )
+        .unwrap();
+        let maildir = Maildir::new(dir.path().join("test"));
+        maildir.create().await.unwrap();
+        maildir
+            .deliver(b"Subject: hi\r\n\r\nhello\r\n")
+            .await
+            .unwrap();
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+
+        for implicit in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (config, store) = (config.clone(), store.clone());
+            tokio::spawn(async move {
+                while let Ok((tcp, _)) = listener.accept().await {
+                    let mut state = Pop3UserState::with_store(store.clone());
+                    state.tls = Some(config.clone());
+                    state.require_tls = true;
+                    state.secure = implicit;
+                    let stream = match implicit {
+                        true => tls::accept(config.clone(), tcp).await.unwrap(),
+                        false => MaybeTls::Plain(tcp),
+                    };
+                    let idle = Duration::from_secs(60);
+                    tokio::spawn(pop3_handler(stream, state, idle));
+                }
+            });
+            let host = format!("localhost:{}", port);
+
+            if !implicit {
+                // Before STLS only STLS is offered, and USER is refused.
+                let tcp = TcpStream::connect(&host).await.unwrap();
+                let mut c = BufReader::new(MaybeTls::Plain(tcp));
+                read_status(&mut c).await.unwrap();
+                c.write_all(b"CAPA\r\n").await.unwrap();
+                read_status(&mut c).await.unwrap();
+                assert_eq!(read_multi_line(&mut c).await.unwrap(), b"STLS\r\n");
+                c.write_all(b"USER test\r\n").await.unwrap();
+                assert!(matches!(
+                    read_status(&mut c).await,
+                    Err(Pop3Error::Server(_))
+                ));
+            }
+
+            let mode = match implicit {
+                true => TlsMode::Implicit,
+                false => TlsMode::StartTls,
+            };
+            let mut pop = tls_client(&host, mode, TlsVerify::Insecure).await;
+            let list = pop.cmd(Pop3Command::LIST).await.unwrap();
+            assert!(list.starts_with(b"1 "), "{:?}", list);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::new(dir.path().join("test"));
+        maildir.create().await.unwrap();
+        maildir.deliver(b"Subject: x\n\n.dot\nend").await.unwrap();
+        maildir.deliver(b"Subject: y\r\n\r\n.\r\n").await.unwrap();
+        let mut state = Pop3UserState::with_store(MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        });
+        let mut send = async |cmd: &[u8]| {
+            let mut w = Vec::new();
+            pop3_handler_state(&mut w, &mut state, cmd, cmd.len())
+                .await
+                .unwrap();
+            w
+        };
+        send(b"USER test\r\n").await;
+
+        // Sizes count CRLF line endings and doubled leading dots.
+        assert_eq!(
+            send(b"LIST\r\n").await,
+            b"+OK 2 messages (44 octets)\r\n1 26\r\n2 18\r\n.\r\n"
+        );
+        assert_eq!(send(b"STAT\r\n").await, b"+OK 2 44\r\n");
+        let uidl = String::from_utf8(send(b"UIDL\r\n").await).unwrap();
+        let lines: Vec<&str> = uidl.split("\r\n").collect();
+        assert_eq!((lines[0], lines[3], lines.len()), ("+OK", ".", 5));
+        assert!(lines[1].starts_with("1 ") && lines[2].starts_with("2 "));
+        assert_ne!(lines[1][2..], lines[2][2..]);
+        assert_eq!(
+            send(b"RETR 1\r\n").await,
+            b"+OK 26 octets\r\nSubject: x\r\n\r\n..dot\r\nend\r\n.\r\n"
+        );
+        assert!(send(b"RETR 2\r\n").await.starts_with(b"+OK 18 octets\r\n"));
+        assert!(send(b"RETR 0\r\n").await.starts_with(b"-ERR"));
+        assert!(send(b"RETR 3\r\n").await.starts_with(b"-ERR"));
+    }
+
+    #[tokio::test]
+    async fn client_server_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::new(dir.path().join("test"));
+        maildir.create().await.unwrap();
+        maildir.deliver(b"Subject: x\n\n.dot\nend").await.unwrap();
+        maildir.deliver(b"Subject: y\r\n\r\n.\r\n").await.unwrap();
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let state = Pop3UserState::with_store(store);
+            let idle = Duration::from_secs(60);
+            pop3_handler(MaybeTls::Plain(tcp), state, idle).await;
+        });
+
+        let mut pop =
+            Pop3Builder::new().email("test").host(&host).build().await;
+        let stat = pop.stat().await.unwrap();
+        assert_eq!((stat.count, stat.size), (2, 44));
+        let list = pop.list().await.unwrap();
+        assert_eq!(
+            list,
+            [
+                ListEntry {
+                    number: 1,
+                    size: 26
+                },
+                ListEntry {
+                    number: 2,
+                    size: 18
+                }
+            ]
+        );
+        assert_eq!(
+            pop.retr(1).await.unwrap(),
+            b"Subject: x\r\n\r\n.dot\r\nend\r\n"
+        );
+        assert_eq!(pop.retr(2).await.unwrap(), b"Subject: y\r\n\r\n.\r\n");
+        assert!(matches!(pop.retr(3).await, Err(Pop3Error::Server(_))));
+        pop.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::new(dir.path().join("alice.z9"));
+        maildir.create().await.unwrap();
+        maildir
+            .deliver(b"Subject: hi\r\n\r\nhello\r\n")
+            .await
+            .unwrap();
+        let store = MailStore::Maildir {
+            path: dir.path().join("{user}").display().to_string(),
+        };
+        let credentials =
+            Arc::new(Credentials::parse("alice.z9:{PLAIN}secret").unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let mut state = Pop3UserState::with_store(store.clone());
+                state.credentials = Some(credentials.clone());
+                let stream = MaybeTls::Plain(tcp);
+                let idle = Duration::from_secs(60);
+                tokio::spawn(pop3_handler(stream, state, idle));
+            }
+        });
+
+        let cases = [
+            (Pop3Auth::Auto, "secret", true),
+            (Pop3Auth::UserPass, "secret", true),
+            (Pop3Auth::Plain, "wrong", false),
+            (Pop3Auth::UserPass, "wrong", false),
+        ];
+        for (auth, password, ok) in cases {
+            let mut pop = Pop3Builder::new()
+                .email("alice.z9")
+                .password(password)
+                .auth(auth)
+                .host(&host)
+                .build()
+                .await;
+            assert_eq!(pop.cmd(Pop3Command::LIST).await.is_ok(), ok);
+        }
+
+        // Every command can go through cmd without panicking.
+        let mut pop = Pop3Builder::new()
+            .email("alice.z9")
+            .password("secret")
+            .host(&host)
+            .build()
+            .await;
+        assert_eq!(pop.cmd(Pop3Command::STAT).await.unwrap(), b"1 22");
+        assert_eq!(
+            pop.cmd(Pop3Command::CAPA).await.unwrap(),
+            b"USER\r\nSASL PLAIN LOGIN CRAM-MD5\r\n"
+        );
+        assert!(pop.cmd(Pop3Command::STLS).await.is_err());
+        let auth = Pop3Command::AUTH(String::from("PLAIN"), None);
+        assert!(pop.cmd(auth).await.is_err());
+
+        let tcp = TcpStream::connect(&host).await.unwrap();
+        let mut c = BufReader::new(MaybeTls::Plain(tcp));
+        read_status(&mut c).await.unwrap();
+        for bad in [&b"USER\r\n"[..], b"USER ../x\r\n", b"USER a\x01b\r\n"] {
+            c.write_all(bad).await.unwrap();
+            assert!(read_status(&mut c).await.is_err());
+        }
+        c.write_all(b"CAPA\r\n").await.unwrap();
+        read_status(&mut c).await.unwrap();
+        assert_eq!(
+            read_multi_line(&mut c).await.unwrap(),
+            b"USER\r\nSASL PLAIN LOGIN CRAM-MD5\r\n"
+        );
+        c.write_all(b"AUTH CRAM-MD5\r\n").await.unwrap();
+        let mut line = Vec::new();
+        read_line(&mut c, &mut line).await.unwrap();
+        let challenge = line.strip_prefix(b"+ ").unwrap().trim_ascii();
+        let mut mac = Hmac::<Md5>::new_from_slice(b"secret").unwrap();
+        mac.update(&base64::decode(challenge).unwrap());
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let response = base64::encode(format!("alice.z9 {}", digest));
+        c.write_all(format!("{}\r\n", response).as_bytes())
+            .await
+            .unwrap();
+        read_status(&mut c).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let state = Pop3UserState::new();
+            let idle = Duration::from_millis(50);
+            pop3_handler(MaybeTls::Plain(tcp), state, idle).await;
+        });
+        let tcp = TcpStream::connect(host).await.unwrap();
+        let mut c = BufReader::new(MaybeTls::Plain(tcp));
+        read_status(&mut c).await.unwrap();
+        c.write_all(b"NOOP\r\n").await.unwrap();
+        read_status(&mut c).await.unwrap();
+        assert!(matches!(
+            read_status(&mut c).await,
+            Err(Pop3Error::Server(text)) if text == "idle timeout"
+        ));
+        let mut rest = Vec::new();
+        assert_eq!(c.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn stls_handshake_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap();
+        let (cert_path, key_path) =
+            (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let config = tls::server_config(cert_path, key_path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut state = Pop3UserState::new();
+            state.tls = Some(config);
+            let idle = Duration::from_millis(50);
+            pop3_handler(MaybeTls::Plain(tcp), state, idle).await;
+        });
+        let tcp = TcpStream::connect(host).await.unwrap();
+        let mut c = BufReader::new(MaybeTls::Plain(tcp));
+        read_status(&mut c).await.unwrap();
+        c.write_all(b"STLS\r\n").await.unwrap();
+        read_status(&mut c).await.unwrap();
+        // Never starting the handshake does not keep the session open.
+        let mut rest = Vec::new();
+        let closed = timeout(Duration::from_secs(5), c.read_to_end(&mut rest));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap();
+        let (stop, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut state = Pop3UserState::new();
+            state.shutdown = Some(shutdown);
+            let idle = Duration::from_secs(60);
+            pop3_handler(MaybeTls::Plain(tcp), state, idle).await;
+        });
+        let tcp = TcpStream::connect(host).await.unwrap();
+        let mut c = BufReader::new(MaybeTls::Plain(tcp));
+        read_status(&mut c).await.unwrap();
+        c.write_all(b"NOOP\r\n").await.unwrap();
+        read_status(&mut c).await.unwrap();
+        stop.send_replace(true);
+        assert!(matches!(
+            read_status(&mut c).await,
+            Err(Pop3Error::Server(text)) if text == "server shutting down"
+        ));
+        let mut rest = Vec::new();
+        assert_eq!(c.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn pop3_test() {
         let mut pop = Pop3Builder::new()
@@ -513,8 +1842,81 @@ mod test {
             .build()
             .await;
 
-        println!("{}", pop.cmd(Pop3Command::LIST).await.unwrap());
+        let list = pop.cmd(Pop3Command::LIST).await.unwrap();
+        println!("{}", String::from_utf8_lossy(&list));
         // pop.cmd(Pop3Command::USER("root".to_string())).await.unwrap();
         // pop.cmd(Pop3Command::RETR(115)).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn status_lines() {
+        let mut r: &[u8] = b"+OK 2 320\r\n+OK\r\n-ERR no such message\r\n\
+            +Ok\r\n-ERRx\r\n";
+        assert_eq!(read_status(&mut r).await.unwrap(), "2 320");
+        assert_eq!(read_status(&mut r).await.unwrap(), "");
+        assert!(matches!(
+            read_status(&mut r).await,
+            Err(Pop3Error::Server(text)) if text == "no such message"
+        ));
+        assert!(matches!(
+            read_status(&mut r).await,
+            Err(Pop3Error::Protocol(_))
+        ));
+        assert!(matches!(
+            read_status(&mut r).await,
+            Err(Pop3Error::Protocol(_))
+        ));
+        assert!(matches!(read_status(&mut r).await, Err(Pop3Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn multi_line_responses() {
+        // Tiny reads end in the middle of lines and right after `foo.`.
+        let data: &[u8] =
+            b"+OK 30 octets\r\nfoo.\r\n..bar\r\n\xff\xfe\r\n.\r\n";
+        let mut r = BufReader::with_capacity(3, data);
+        assert_eq!(
+            read_content(&mut r).await.unwrap(),
+            b"foo.\r\n.bar\r\n\xff\xfe\r\n"
+        );
+
+        // Older versions of our own server sent bodies without a status line.
+        let mut r: &[u8] = b"0 120\r\n.\r\n.\r\n-ERR no such message\r\n";
+        assert_eq!(read_content(&mut r).await.unwrap(), b"0 120\r\n");
+        assert_eq!(read_content(&mut r).await.unwrap(), b"");
+        assert!(matches!(
+            read_content(&mut r).await,
+            Err(Pop3Error::Server(_))
+        ));
+
+        let mut r: &[u8] = b"+OK\r\ncut short\r\n";
+        assert!(matches!(read_content(&mut r).await, Err(Pop3Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn listings() {
+        let mut r: &[u8] = b"1 120\r\n2 200\r\n.\r\n";
+        let body = read_multi_line(&mut r).await.unwrap();
+        let list = scan_listing(&body, |number, size| {
+            Some(ListEntry {
+                number,
+                size: size.parse().ok()?,
+            })
+        })
+        .unwrap();
+        assert_eq!(
+            list,
+            [
+                ListEntry {
+                    number: 1,
+                    size: 120
+                },
+                ListEntry {
+                    number: 2,
+                    size: 200
+                }
+            ]
+        );
+        assert!(scan_listing(b"1\r\n", |n, _| Some(n)).is_err());
+    }
 }
@@ -4,15 +4,20 @@ use std::time::Duration;
 use base64::encode;
 use bytes::BufMut;
 use encoding::{EncoderTrap, Encoding};
+use futures_util::{Stream, StreamExt};
 use tokio::{
     fs::File,
-    io::{self, AsyncReadExt, AsyncWriteExt},
+    io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     time::timeout,
 };
 
-use crate::mime::{
-    mime_encode, Alternative, ContentTransferEncoding, ContentType,
+use crate::{
+    address::{parse_address_list, Mailbox},
+    mime::{
+        required_addresses, Alternative, ContentTransferEncoding, ContentType,
+        Headers, MediaType, MimeEncoder,
+    },
 };
 
 #[derive(Debug)]
@@ -27,16 +32,44 @@ pub struct SmtpClient {
 }
 
 impl SmtpClient {
+    /// Sends a message. `Date` and `Message-ID` are generated unless set in
+    /// `headers`, with Message-IDs in the domain given to `address`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn send(
         &mut self,
         from: &str,
         to: &str,
         subject: &str,
         cte: ContentTransferEncoding,
-        content_type: ContentType,
+        content_type: impl Into<MediaType>,
         content: &str,
         attach: Option<&[Alternative]>,
+        headers: Option<&Headers>,
     ) -> Result<(), ()> {
+        let mut headers = headers.cloned().unwrap_or_default();
+        if headers.host.is_none() {
+            headers.host = self.address.clone();
+        }
+        let envelope = required_addresses(from, to).and_then(|(f, mut t)| {
+            let sender = f[0].mailboxes()[0].clone();
+            if let Some(ref cc) = headers.cc {
+                t.extend(parse_address_list(cc)?);
+            }
+            let recipients: Vec<Mailbox> =
+                t.iter().flat_map(|a| a.mailboxes()).cloned().collect();
+            if recipients.is_empty() {
+                return Err("no recipients");
+            }
+            Ok((sender, recipients))
+        });
+        let ((sender, recipients), headers) =
+            match envelope.and_then(|e| Ok((e, headers.resolve()?))) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Err(());
+                }
+            };
         if self.upstream.is_none() {
             match smtp_upstream_connect(self).await {
                 Ok(c) => {
@@ -63,9 +96,12 @@ impl SmtpClient {
             to,
             subject,
             cte,
-            content_type,
+            content_type.into(),
             content,
             attach,
+            &headers,
+            &sender,
+            &recipients,
         )
         .await
     }
@@ -136,9 +172,19 @@ impl SmtpBuilder {
     }
 }
 
+/// Terminates a command line with CRLF. Every command goes through here so
+/// no value can end a line early and smuggle in another command.
+fn command(line: &str) -> Result<Vec<u8>, &'static str> {
+    if line.bytes().any(|b| b < 0x20 || b == 0x7f) {
+        return Err("control character in SMTP command");
+    }
+    Ok(format!("{}\r\n", line).into_bytes())
+}
+
 async fn smtp_upstream_connect(
     smtp: &mut SmtpClient,
 ) -> Result<TcpStream, &'static str> {
+    let helo = command(&format!("HELO {}", smtp.address.as_ref().unwrap()))?;
     for _ in 0..5 {
         match timeout(Duration::from_millis(500), async {
             TcpStream::connect(smtp.host.as_ref().unwrap())
@@ -153,12 +199,7 @@ async fn smtp_upstream_connect(
                 if !buf.starts_with(b"220") {
                     return Err("connection refused");
                 }
-                c.write(
-                    format!("HELO {}\r\n", smtp.address.as_ref().unwrap())
-                        .as_bytes(),
-                )
-                .await
-                .unwrap();
+                c.write(&helo).await.unwrap();
                 loop {
                     match c.read(buf).await {
                         Ok(n) if n == 0 => {
@@ -179,28 +220,20 @@ async fn smtp_upstream_connect(
                         }
                     }
                 }
-                if smtp.token.is_some() {
+                if let Some(token) = &smtp.token {
                     c.write(b"AUTH LOGIN\r\n").await.unwrap();
                     c.read(buf).await.unwrap();
                     if !buf.starts_with(b"334") {
                         return Err("auth login error");
                     }
-                    c.write(
-                        format!("{}\r\n", smtp.email.as_ref().unwrap())
-                            .as_bytes(),
-                    )
-                    .await
-                    .unwrap();
+                    c.write(&command(smtp.email.as_ref().unwrap())?)
+                        .await
+                        .unwrap();
                     c.read(buf).await.unwrap();
                     if !buf.starts_with(b"334") {
                         return Err("auth login error2");
                     }
-                    c.write(
-                        format!("{}\r\n", smtp.token.as_ref().unwrap())
-                            .as_bytes(),
-                    )
-                    .await
-                    .unwrap();
+                    c.write(&command(token)?).await.unwrap();
                     c.read(buf).await.unwrap();
                     if !buf.starts_with(b"235") {
                         return Err("authentication failure");
@@ -217,28 +250,79 @@ async fn smtp_upstream_connect(
     Err("timeout 5 times")
 }
 
+/// Doubles a `.` at the start of a line (RFC 5321 section 4.5.2), so the
+/// message cannot end DATA early.
+struct DotStuffer {
+    line_start: bool,
+}
+
+impl DotStuffer {
+    fn new() -> Self {
+        Self { line_start: true }
+    }
+
+    fn stuff(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 8);
+        for &b in data {
+            if self.line_start && b == b'.' {
+                out.push(b'.');
+            }
+            out.push(b);
+            self.line_start = b == b'\n';
+        }
+        out
+    }
+}
+
+/// Writes the chunks of a message as DATA, dot-stuffed.
+async fn write_data(
+    mut message: impl Stream<Item = io::Result<bytes::Bytes>> + Unpin,
+    w: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+    let mut stuffer = DotStuffer::new();
+    while let Some(chunk) = message.next().await {
+        w.write_all(&stuffer.stuff(&chunk?)).await?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn smtp_upstream_send(
     smtp: &mut SmtpClient,
     from: &str,
     to: &str,
     subject: &str,
     content_transfer_encoding: ContentTransferEncoding,
-    content_type: ContentType,
+    content_type: MediaType,
     content: &str,
     attach: Option<&[Alternative]>,
+    headers: &Headers,
+    sender: &Mailbox,
+    recipients: &[Mailbox],
 ) -> Result<(), ()> {
     let mut c = smtp.upstream.as_mut().unwrap();
     let buf = &mut smtp.buf;
 
-    c.write(format!("MAIL FROM: <{}>\r\n", from).as_bytes())
-        .await
-        .unwrap();
-    c.read(buf).await.unwrap();
-
-    c.write(format!("RCPT TO: <{}>\r\n", to).as_bytes())
-        .await
-        .unwrap();
-    c.read(buf).await.unwrap();
+    let mail = format!("MAIL FROM: <{}>", sender.addr_spec());
+    let envelope: Result<Vec<_>, _> = std::iter::once(mail)
+        .chain(
+            recipients
+                .iter()
+                .map(|rcpt| format!("RCPT TO: <{}>", rcpt.addr_spec())),
+        )
+        .map(|line| command(&line))
+        .collect();
+    let envelope = match envelope {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(());
+        }
+    };
+    for line in envelope {
+        c.write(&line).await.unwrap();
+        c.read(buf).await.unwrap();
+    }
 
     c.write(b"DATA\r\n").await.unwrap();
     loop {
@@ -261,7 +345,9 @@ async fn smtp_upstream_send(
         }
     }
 
-    match mime_encode(
+    // Attachments are encoded while they are written, so a failure here
+    // leaves DATA half sent and the connection must be dropped.
+    let encoder = MimeEncoder::new(
         from,
         to,
         subject,
@@ -269,16 +355,12 @@ async fn smtp_upstream_send(
         content_type,
         content,
         attach,
-    )
-    .await
-    {
-        Ok(encoded) => {
-            c.write_all(&encoded).await.unwrap();
-        }
-        Err(_) => {
-            smtp.upstream = None;
-            return Err(());
-        }
+        Some(headers),
+    );
+    if let Err(e) = write_data(encoder, c).await {
+        eprintln!("{}", e);
+        smtp.upstream = None;
+        return Err(());
     }
 
     c.write(b"\r\n.\r\n").await.unwrap();
@@ -292,6 +374,34 @@ async fn smtp_upstream_send(
 mod test {
     use super::*;
 
+    #[test]
+    fn command_rejects_line_breaks() {
+        assert_eq!(command("HELO jckeep.top").unwrap(), b"HELO jckeep.top\r\n");
+        for bad in ["\r\nRCPT TO:<x@y>", "\nDATA", "\rQUIT", "\0"] {
+            assert!(command(&format!("HELO a{}", bad)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn data_is_dot_stuffed() {
+        let body = "first\n.\n..two\nlast\n.";
+        let encoder = MimeEncoder::new(
+            "a@example.com",
+            "b@example.com",
+            "dots",
+            ContentTransferEncoding::Bit7,
+            ContentType::TextPlain,
+            body,
+            None,
+            None,
+        );
+        let mut data = Vec::new();
+        write_data(encoder, &mut data).await.unwrap();
+        assert!(data.ends_with(b"\r\nfirst\r\n..\r\n...two\r\nlast\r\n.."));
+        assert!(!data.windows(5).any(|w| w == b"\r\n.\r\n"));
+        assert_eq!(DotStuffer::new().stuff(b".a\r\nb."), b"..a\r\nb.");
+    }
+
     #[tokio::test]
     async fn smtp_test() {
         let mut smtp = SmtpBuilder::new()
@@ -304,26 +414,21 @@ mod test {
         let a = Alternative {
             filename: None,
             content: HTML.to_string(),
-            content_type: ContentType::TextHtml,
+            source: None,
+            charset: None,
+            content_type: ContentType::TextHtml.into(),
             encoding: ContentTransferEncoding::QuotedPrintable,
+            disposition: None,
+            content_id: None,
         };
-        let b = Alternative {
-            filename: Some(String::from("传输层.pdf")),
-            content: "".to_string(),
-            content_type: ContentType::ApplicationPdf,
-            encoding: ContentTransferEncoding::Base64,
-        };
+        let b = Alternative::from_path("传输层.pdf");
         let c = Alternative {
-            filename: Some(String::from("头像.jpeg")),
-            content: "".to_string(),
-            content_type: ContentType::ImageJpeg,
             encoding: ContentTransferEncoding::Bit7,
+            ..Alternative::from_path("头像.jpeg")
         };
         let d = Alternative {
-            filename: Some(String::from("src/main.rs")),
-            content: "".to_string(),
-            content_type: ContentType::TextPlain,
             encoding: ContentTransferEncoding::Bit7,
+            ..Alternative::from_path("src/main.rs")
         };
         let v = vec![a, c, d];
 
@@ -338,6 +443,7 @@ mod test {
             ContentType::MultipartMixed,
             HTML,
             Some(&v),
+            None,
         )
         .await;
     }
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use crate::message::{normalize_subject, Message};
+
+/// A node of a conversation tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thread {
+    /// Index into the threaded messages, `None` for a message that is
+    /// referenced but was not among them.
+    pub message: Option<usize>,
+    pub children: Vec<Thread>,
+}
+
+/// Working node of the id table, linked by indices.
+struct Container {
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Threads messages following Jamie Zawinski's algorithm. Messages are
+/// linked by Message-ID, References and In-Reply-To, placeholders for
+/// missing messages are kept only where they join several replies, and the
+/// remaining roots are grouped by normalised subject. Siblings are ordered
+/// by date.
+pub fn thread_messages(messages: &[Message]) -> Vec<Thread> {
+    let mut nodes: Vec<Container> = Vec::new();
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    let new_node = |nodes: &mut Vec<Container>| {
+        nodes.push(Container {
+            message: None,
+            parent: None,
+            children: Vec::new(),
+        });
+        nodes.len() - 1
+    };
+
+    for (i, m) in messages.iter().enumerate() {
+        let node = match m.message_id() {
+            Some(id) => match ids.get(&id) {
+                Some(&n) if nodes[n].message.is_none() => n,
+                // A duplicate Message-ID is threaded as a message of its own.
+                Some(_) => new_node(&mut nodes),
+                None => {
+                    let n = new_node(&mut nodes);
+                    ids.insert(id, n);
+                    n
+                }
+            },
+            None => new_node(&mut nodes),
+        };
+        nodes[node].message = Some(i);
+
+        let mut refs = m.references();
+        for id in m.in_reply_to() {
+            if !refs.contains(&id) {
+                refs.push(id);
+            }
+        }
+        let mut prev = None;
+        for id in &refs {
+            let n = match ids.get(id) {
+                Some(&n) => n,
+                None => {
+                    let n = new_node(&mut nodes);
+                    ids.insert(id.clone(), n);
+                    n
+                }
+            };
+            // Links guessed from References never override earlier ones.
+            if let Some(p) = prev {
+                if nodes[n].parent.is_none() && !is_ancestor(&nodes, n, p) {
+                    link(&mut nodes, p, n);
+                }
+            }
+            prev = Some(n);
+        }
+        // The message's own References are authoritative for its parent.
+        unlink(&mut nodes, node);
+        if let Some(p) = prev {
+            if !is_ancestor(&nodes, node, p) {
+                link(&mut nodes, p, node);
+            }
+        }
+    }
+
+    let roots: Vec<Thread> = (0..nodes.len())
+        .filter(|&n| nodes[n].parent.is_none())
+        .map(|n| build(&nodes, n))
+        .collect();
+    let mut roots = group_by_subject(prune(roots, true), messages);
+    sort(&mut roots, messages);
+    roots
+}
+
+/// Whether `a` is `b` or one of its ancestors.
+fn is_ancestor(nodes: &[Container], a: usize, b: usize) -> bool {
+    let mut cur = Some(b);
+    while let Some(n) = cur {
+        if n == a {
+            return true;
+        }
+        cur = nodes[n].parent;
+    }
+    false
+}
+
+fn link(nodes: &mut [Container], parent: usize, child: usize) {
+    nodes[child].parent = Some(parent);
+    nodes[parent].children.push(child);
+}
+
+fn unlink(nodes: &mut [Container], child: usize) {
+    if let Some(parent) = nodes[child].parent.take() {
+        nodes[parent].children.retain(|&c| c != child);
+    }
+}
+
+fn build(nodes: &[Container], n: usize) -> Thread {
+    Thread {
+        message: nodes[n].message,
+        children: nodes[n].children.iter().map(|&c| build(nodes, c)).collect(),
+    }
+}
+
+/// Removes empty containers, promoting their children. An empty root with
+/// several children stays to hold them together.
+fn prune(threads: Vec<Thread>, root: bool) -> Vec<Thread> {
+    let mut pruned = Vec::new();
+    for mut t in threads {
+        t.children = prune(t.children, false);
+        if t.message.is_none() && (!root || t.children.len() < 2) {
+            pruned.append(&mut t.children);
+        } else {
+            pruned.push(t);
+        }
+    }
+    pruned
+}
+
+/// Subject of a root, taken from its first child for an empty one.
+fn subject(t: &Thread, messages: &[Message]) -> String {
+    match t.message {
+        Some(i) => messages[i].subject(),
+        None => t
+            .children
+            .first()
+            .map(|c| subject(c, messages))
+            .unwrap_or_default(),
+    }
+}
+
+fn group_by_subject(roots: Vec<Thread>, messages: &[Message]) -> Vec<Thread> {
+    let subjects: Vec<String> =
+        roots.iter().map(|t| subject(t, messages)).collect();
+    // A root that holds a message whose subject has a Re: or Fwd: prefix.
+    let reply: Vec<bool> = roots
+        .iter()
+        .zip(&subjects)
+        .map(|(t, s)| t.message.is_some() && normalize_subject(s) != s.trim())
+        .collect();
+    let mut roots: Vec<Option<Thread>> = roots.into_iter().map(Some).collect();
+
+    // Pick one root per subject, preferring empty ones and then originals
+    // over replies.
+    let mut table: HashMap<&str, usize> = HashMap::new();
+    for i in 0..roots.len() {
+        let key = normalize_subject(&subjects[i]);
+        if key.is_empty() {
+            continue;
+        }
+        match table.get(key) {
+            None => {
+                table.insert(key, i);
+            }
+            Some(&j) => {
+                let empty = |k: usize| {
+                    roots[k].as_ref().is_some_and(|t| t.message.is_none())
+                };
+                if (empty(i) && !empty(j)) || (reply[j] && !reply[i]) {
+                    table.insert(key, i);
+                }
+            }
+        }
+    }
+
+    for i in 0..roots.len() {
+        let j = match table.get(normalize_subject(&subjects[i])) {
+            Some(&j) if j != i => j,
+            _ => continue,
+        };
+        let this = roots[i].take().unwrap();
+        let other = roots[j].as_mut().unwrap();
+        if other.message.is_none() && this.message.is_none() {
+            other.children.extend(this.children);
+        } else if other.message.is_none() || (reply[i] && !reply[j]) {
+            other.children.push(this);
+        } else {
+            let other = roots[j].take().unwrap();
+            roots[j] = Some(Thread {
+                message: None,
+                children: vec![other, this],
+            });
+        }
+    }
+    roots.into_iter().flatten().collect()
+}
+
+/// Orders siblings by the date of their message, or of the first child for
+/// an empty container. Undated messages keep their relative order.
+fn sort(threads: &mut [Thread], messages: &[Message]) {
+    for t in threads.iter_mut() {
+        sort(&mut t.children, messages);
+    }
+    fn key(t: &Thread, messages: &[Message]) -> (i64, usize) {
+        match t.message {
+            Some(i) => {
+                let date = messages[i].date().map(|d| d.timestamp());
+                (date.unwrap_or(i64::MAX), i)
+            }
+            None => t
+                .children
+                .first()
+                .map(|c| key(c, messages))
+                .unwrap_or((i64::MAX, usize::MAX)),
+        }
+    }
+    threads.sort_by_key(|t| key(t, messages));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mail(id: &str, refs: &str, subject: &str, date: u32) -> Message {
+        let mut raw = format!(
+            "From: a@example.com\r\nSubject: {}\r\n\
+             Date: Mon, 19 Oct 2026 08:{:02}:00 +0800\r\n",
+            subject, date
+        );
+        if !id.is_empty() {
+            raw.push_str(&format!("Message-ID: <{}@x>\r\n", id));
+        }
+        if !refs.is_empty() {
+            let refs: Vec<String> =
+                refs.split(' ').map(|r| format!("<{}@x>", r)).collect();
+            raw.push_str(&format!("References: {}\r\n", refs.join(" ")));
+        }
+        raw.push_str("\r\nbody\r\n");
+        Message::parse(raw.as_bytes()).unwrap()
+    }
+
+    fn leaf(i: usize) -> Thread {
+        Thread {
+            message: Some(i),
+            children: vec![],
+        }
+    }
+
+    fn node(message: Option<usize>, children: Vec<Thread>) -> Thread {
+        Thread { message, children }
+    }
+
+    #[test]
+    fn reply_chain() {
+        let messages = [
+            mail("c", "a b", "Re: disk", 3),
+            mail("a", "", "disk", 1),
+            mail("b", "a", "Re: disk", 2),
+            mail("d", "a", "Re: disk", 4),
+        ];
+        assert_eq!(
+            thread_messages(&messages),
+            [node(Some(1), vec![node(Some(2), vec![leaf(0)]), leaf(3)])]
+        );
+    }
+
+    #[test]
+    fn missing_parent() {
+        let messages = [
+            mail("b", "x", "Re: cpu", 2),
+            mail("c", "x", "Re: cpu", 1),
+            mail("d", "y", "Re: mem", 3),
+        ];
+        assert_eq!(
+            thread_messages(&messages),
+            [node(None, vec![leaf(1), leaf(0)]), leaf(2)]
+        );
+    }
+
+    #[test]
+    fn subject_grouping() {
+        let messages = [
+            mail("b", "", "Re: 磁盘告警", 2),
+            mail("a", "", "磁盘告警", 1),
+            mail("c", "", "回复：磁盘告警", 3),
+            mail("d", "", "disk", 4),
+            mail("e", "", "disk", 5),
+        ];
+        assert_eq!(
+            thread_messages(&messages),
+            [
+                node(Some(1), vec![leaf(0), leaf(2)]),
+                node(None, vec![leaf(3), leaf(4)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_and_duplicates() {
+        let messages = [
+            mail("a", "b", "x", 1),
+            mail("b", "a", "y", 2),
+            mail("a", "", "z", 3),
+            mail("", "", "w", 4),
+        ];
+        let threads = thread_messages(&messages);
+        fn count(t: &[Thread]) -> usize {
+            t.iter()
+                .map(|t| t.message.iter().count() + count(&t.children))
+                .sum()
+        }
+        assert_eq!(count(&threads), 4);
+        assert_eq!(threads.len(), 3);
+    }
+}
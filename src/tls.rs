@@ -0,0 +1,257 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{
+            HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+        },
+        crypto::{
+            ring, verify_tls12_signature, verify_tls13_signature,
+            CryptoProvider,
+        },
+        pki_types::{
+            pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime,
+        },
+        ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+        SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector, TlsStream,
+};
+
+/// When a connection switches to TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plain text throughout.
+    #[default]
+    None,
+    /// TLS from the first byte, as on port 995.
+    Implicit,
+    /// Plain text until the STLS command upgrades the connection.
+    StartTls,
+}
+
+/// How a client checks the server's certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TlsVerify {
+    /// Against the Mozilla root certificates.
+    #[default]
+    WebPki,
+    /// Against the CA certificates in a PEM file, for self-hosted servers
+    /// with their own CA or a self-signed certificate.
+    CaFile(PathBuf),
+    /// Not at all. Signatures are still checked, but anyone in the path
+    /// can impersonate the server.
+    Insecure,
+}
+
+/// A TCP connection that may have been upgraded to TLS.
+#[derive(Debug)]
+pub enum MaybeTls {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTls {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTls::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTls {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTls::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTls::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTls::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Accepts any certificate, for [`TlsVerify::Insecure`].
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &self.0.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &self.0.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A client configuration verifying servers as `verify` says.
+pub fn client_config(verify: &TlsVerify) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let config = match verify {
+        TlsVerify::WebPki => {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsVerify::CaFile(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in
+                CertificateDer::pem_file_iter(path).map_err(invalid_data)?
+            {
+                roots
+                    .add(cert.map_err(invalid_data)?)
+                    .map_err(invalid_data)?;
+            }
+            if roots.is_empty() {
+                return Err(invalid_data(format!(
+                    "{}: no certificates",
+                    path.display()
+                )));
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsVerify::Insecure => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider())))
+            .with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Performs the client side of a TLS handshake. `host` may carry a port,
+/// which is ignored.
+pub async fn connect(
+    config: Arc<ClientConfig>,
+    host: &str,
+    stream: TcpStream,
+) -> io::Result<MaybeTls> {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|_| invalid_data(format!("invalid server name {}", name)))?;
+    let stream = TlsConnector::from(config).connect(name, stream).await?;
+    Ok(MaybeTls::Tls(Box::new(stream.into())))
+}
+
+/// A server configuration presenting the certificate chain in `cert` with
+/// the private key in `key`, both PEM files.
+pub fn server_config(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let (cert, key) = (cert.as_ref(), key.as_ref());
+    let in_file = |path: &Path| {
+        let path = path.display().to_string();
+        move |e: rustls::pki_types::pem::Error| {
+            invalid_data(format!("{}: {}", path, e))
+        }
+    };
+    let chain = CertificateDer::pem_file_iter(cert)
+        .map_err(in_file(cert))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(in_file(cert))?;
+    if chain.is_empty() {
+        return Err(invalid_data(format!(
+            "{}: no certificates",
+            cert.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(in_file(key))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Performs the server side of a TLS handshake.
+pub async fn accept(
+    config: Arc<ServerConfig>,
+    stream: TcpStream,
+) -> io::Result<MaybeTls> {
+    let stream = TlsAcceptor::from(config).accept(stream).await?;
+    Ok(MaybeTls::Tls(Box::new(stream.into())))
+}